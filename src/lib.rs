@@ -1 +1,3 @@
 pub mod monitor;
+pub mod report;
+pub mod runner;
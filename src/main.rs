@@ -1,31 +1,253 @@
-use std::{env, path::Path};
+use std::{env, fs, path::Path};
 
 use anyhow::bail;
 use walrus::Module;
-use wasm_bytecode_instrumenter::monitor::{add_monitor, Monitor};
+use wasm_bytecode_instrumenter::{
+    monitor::{
+        add_monitor, counts_size, instrument, Access, Backend, Format, Granularity, Monitor,
+        Options, Pattern, Probe, Registry, Sampling, Switch,
+    },
+    report::report,
+    runner::run,
+};
+
+const USAGE: &str = "Usage:
+    ./bytecode-rewrite <monitors> <filename> [options]
+    ./bytecode-rewrite report <monitors> <filename> <memory dump> [options]
+    ./bytecode-rewrite run <monitors> <filename> [--invoke <export>] [--dump <memory dump>] [--trace <csv>] [options]
+
+Several monitors are added at once by separating them with commas, e.g. hotness,branches,calls.
+
+Options:
+    --backend <memory|single|host|globals>    How probes record their counts (default: memory)
+    --granularity <instr|block|edge>          Count per instruction, per basic block (hotness only) or on
+                                              spanning tree chords of the control flow graph (default: instr)
+    --call-sites <on|off>                     Count every call instruction too (calls only, default: off)
+    --predictors <on|off>                     Simulate 1-bit and 2-bit branch predictors at runtime
+                                              (branches only, memory and single backends, default: off)
+    --selects <on|off>                        Count `select` as a two-way branch (branches only, default: off)
+    --access <count|range|trace>              Record access counts, address ranges or a trace via host calls
+                                              (memory only, default: count)
+    --bucket <bytes>                          Size of the memory regions counted together, a power of two
+                                              up to 2 GiB (heatmap only, default: 4096)
+    --format <text|dot|json|csv>              Report format (callgraph, heatmap and opcodes only,
+                                              default: text)
+    --include <pattern>                       Only instrument matching functions (repeatable)
+    --exclude <pattern>                       Leave matching functions alone (repeatable)
+    --exported <on|off>                       Only instrument exported functions (default: off)
+    --sample <period>[:<burst>]               Record only every period-th probe execution, or bursts of that
+                                              many out of every period, scaling counts up in reports
+                                              (memory and single backends only, default: record all)
+    --switch <none|enabled|disabled>          Export the instrument_enabled global probes check before
+                                              recording, with instrument_enable and instrument_reset
+                                              functions, and its initial state (default: none)
+
+Function patterns are an exact name, a glob (e.g. 'parse_*'), a regex between slashes (e.g. '/^std::/')
+or a function index or range (e.g. '#12' or '#10-20').";
 
 fn main() -> walrus::Result<()> {
     let args: Vec<String> = env::args().skip(1).collect();
+    let registry = Registry::default();
+
+    match args.first().map(|arg| &arg[..]) {
+        Some("report") if args.len() >= 4 => report_command(&registry, &args[1..]),
+        Some("run") if args.len() >= 3 => run_command(&registry, &args[1..]),
+        Some(_) if args.len() >= 2 => {
+            let monitors = parse_monitors(&registry, &args[0])?;
+            let path = Path::new(&args[1]);
+            let module = parse_module(path)?;
+
+            let mut options = Options::default();
+            for (flag, value) in parse_flags(&args[2..])? {
+                parse_option(&mut options, flag, value)?;
+            }
+
+            let probes = add_monitor(module, &monitors, &options, path)?;
+            warn_skipped(&probes);
+
+            Ok(())
+        }
+        _ => bail!(USAGE),
+    }
+}
+
+/// Prints the report for a memory dump of an instrumented module.
+fn report_command(registry: &Registry, args: &[String]) -> walrus::Result<()> {
+    let monitors = parse_monitors(registry, &args[0])?;
+    let module = parse_module(Path::new(&args[1]))?;
+    let memory = match fs::read(&args[2]) {
+        Ok(memory) => memory,
+        _ => bail!("Unable to read memory dump {}", args[2]),
+    };
 
-    if args.len() != 2 {
-        bail!("Usage: ./bytecode-rewrite <monitor> <filename>");
+    let mut options = Options::default();
+    for (flag, value) in parse_flags(&args[3..])? {
+        parse_option(&mut options, flag, value)?;
     }
 
+    print!("{}", report(module, &monitors, &options, &memory)?);
+    Ok(())
+}
+
+/// Instruments and runs a module, then either dumps the instrument
+/// memory or prints the report for it. Memory accesses traced by the
+/// memory monitor can be written to a CSV file.
+fn run_command(registry: &Registry, args: &[String]) -> walrus::Result<()> {
+    let monitors = parse_monitors(registry, &args[0])?;
     let path = Path::new(&args[1]);
+
+    let mut options = Options::default();
+    let mut entry = "_start";
+    let mut dump = None;
+    let mut trace = None;
+    for (flag, value) in parse_flags(&args[2..])? {
+        match flag {
+            "--invoke" => entry = value,
+            "--dump" => dump = Some(value),
+            "--trace" => trace = Some(value),
+            _ => parse_option(&mut options, flag, value)?,
+        }
+    }
+
+    let (mut module, probes) = instrument(parse_module(path)?, &monitors, &options)?;
+    warn_skipped(&probes);
+    let mut execution = run(&module.emit_wasm(), entry)?;
+    if execution.exit_code != 0 {
+        eprintln!("Module exited with code {}", execution.exit_code);
+    }
+
+    if let Some(trace) = trace {
+        let mut csv = String::from("id,address,size\n");
+        for [id, address, size] in &execution.trace {
+            csv += &format!("{},{},{}\n", id, address, size);
+        }
+        fs::write(trace, csv)?;
+    }
+
+    // Counts collected by the host end at the last probe counted, pad
+    // them to the full layout
+    let size = probes
+        .iter()
+        .map(|probes| counts_size(probes))
+        .max()
+        .unwrap_or(0);
+    if execution.instrument.len() < size {
+        execution.instrument.resize(size, 0);
+    }
+
+    match dump {
+        Some(dump) => fs::write(dump, &execution.instrument)?,
+        None => print!(
+            "{}",
+            report(
+                parse_module(path)?,
+                &monitors,
+                &options,
+                &execution.instrument
+            )?
+        ),
+    }
+
+    Ok(())
+}
+
+/// Splits `--flag value` pairs.
+fn parse_flags(args: &[String]) -> walrus::Result<Vec<(&str, &str)>> {
+    if !args.len().is_multiple_of(2) || args.iter().step_by(2).any(|flag| !flag.starts_with("--")) {
+        bail!(USAGE);
+    }
+
+    Ok(args
+        .chunks(2)
+        .map(|pair| (&pair[0][..], &pair[1][..]))
+        .collect())
+}
+
+/// Applies an option shared by all commands.
+fn parse_option(options: &mut Options, flag: &str, value: &str) -> walrus::Result<()> {
+    match (flag, value) {
+        ("--backend", "memory") => options.backend = Backend::Memory,
+        ("--backend", "single") => options.backend = Backend::SingleMemory,
+        ("--backend", "host") => options.backend = Backend::HostCall,
+        ("--backend", "globals") => options.backend = Backend::Globals,
+        ("--backend", backend) => bail!("Invalid backend {}", backend),
+        ("--granularity", "instr") => options.granularity = Granularity::Instruction,
+        ("--granularity", "block") => options.granularity = Granularity::BasicBlock,
+        ("--granularity", "edge") => options.granularity = Granularity::Edge,
+        ("--granularity", granularity) => bail!("Invalid granularity {}", granularity),
+        ("--call-sites", "on") => options.call_sites = true,
+        ("--call-sites", "off") => options.call_sites = false,
+        ("--call-sites", value) => bail!("Invalid call sites setting {}", value),
+        ("--predictors", "on") => options.predictors = true,
+        ("--predictors", "off") => options.predictors = false,
+        ("--predictors", value) => bail!("Invalid predictors setting {}", value),
+        ("--selects", "on") => options.selects = true,
+        ("--selects", "off") => options.selects = false,
+        ("--selects", value) => bail!("Invalid selects setting {}", value),
+        ("--access", "count") => options.access = Access::Count,
+        ("--access", "range") => options.access = Access::Range,
+        ("--access", "trace") => options.access = Access::Trace,
+        ("--access", access) => bail!("Invalid access setting {}", access),
+        ("--bucket", size) => match size.parse() {
+            Ok(size) => options.bucket_size = size,
+            _ => bail!("Invalid bucket size {}", size),
+        },
+        ("--format", "text") => options.format = Format::Text,
+        ("--format", "dot") => options.format = Format::Dot,
+        ("--format", "json") => options.format = Format::Json,
+        ("--format", "csv") => options.format = Format::Csv,
+        ("--format", format) => bail!("Invalid format {}", format),
+        ("--include", pattern) => options.functions.include.push(Pattern::parse(pattern)?),
+        ("--exclude", pattern) => options.functions.exclude.push(Pattern::parse(pattern)?),
+        ("--exported", "on") => options.functions.exported = true,
+        ("--exported", "off") => options.functions.exported = false,
+        ("--exported", value) => bail!("Invalid exported setting {}", value),
+        ("--sample", sampling) => {
+            let (period, burst) = sampling.split_once(':').unwrap_or((sampling, "1"));
+            options.sampling = match (period.parse(), burst.parse()) {
+                (Ok(period), Ok(burst)) => Some(Sampling::new(period, burst)?),
+                _ => bail!("Invalid sampling {}", sampling),
+            }
+        }
+        ("--switch", "none") => options.switch = Switch::Absent,
+        ("--switch", "enabled") => options.switch = Switch::Enabled,
+        ("--switch", "disabled") => options.switch = Switch::Disabled,
+        ("--switch", switch) => bail!("Invalid switch {}", switch),
+        (flag, _) => bail!("Invalid option {}\n\n{}", flag, USAGE),
+    }
+
+    Ok(())
+}
+
+/// Looks up the comma separated monitors.
+fn parse_monitors<'a>(registry: &'a Registry, names: &str) -> walrus::Result<Vec<&'a dyn Monitor>> {
+    names
+        .split(',')
+        .map(|name| match registry.get(name) {
+            Some(monitor) => Ok(monitor),
+            None => bail!("Invalid monitor {}", name),
+        })
+        .collect()
+}
+
+/// Warns about the functions left alone by the monitors.
+fn warn_skipped(probes: &[Vec<Probe>]) {
+    for probe in probes.iter().flatten().filter(|probe| probe.skipped()) {
+        eprintln!(
+            "Skipped function {} with {} paths, too many to profile",
+            probe.func.index(),
+            probe.outcome
+        );
+    }
+}
+
+fn parse_module(path: &Path) -> walrus::Result<Module> {
     if !path.exists() {
         bail!("File does not exist");
     }
 
-    let monitor: Monitor = match &args[0][..] {
-        "branches" => Monitor::Branch,
-        "hotness" => Monitor::Hotness,
-        name => bail!("Invalid monitor {}", name),
-    };
-
-    let module = match Module::from_file(path) {
-        Ok(module) => module,
+    match Module::from_file(path) {
+        Ok(module) => Ok(module),
         _ => bail!("Unable to parse module {:?}", path),
-    };
-
-    add_monitor(module, monitor, path)
+    }
 }
@@ -1,46 +1,747 @@
 mod branch;
+mod callgraph;
+mod calls;
+mod cfg;
+mod compose;
+mod counters;
+mod edges;
+mod filter;
+mod heatmap;
 mod hotness;
+mod r#loop;
+mod memory;
+mod opcodes;
+mod path;
+mod sites;
 
-use std::path::{Path, PathBuf};
+use std::{
+    fs,
+    path::{Path, PathBuf},
+};
 
-use walrus::Module;
+use anyhow::bail;
+use serde_json::json;
+use walrus::{
+    ir::{Instr, InstrSeqId},
+    FunctionId, Module,
+};
 
-pub enum Monitor {
+pub use counters::Counters;
+pub use filter::{Filter, Pattern};
+pub use sites::{instrument_sites, Sites};
+
+/// An analysis that instruments a module and decodes the counts its
+/// probes captured. Monitors written outside this crate implement it
+/// (typically walking the module with `instrument_sites`) and are
+/// added to a `Registry` next to the built-in ones.
+pub trait Monitor {
+    /// Name of the monitor, used on the command line and in the names
+    /// of the files written by `add_monitor`
+    fn name(&self) -> &str;
+
+    /// Adds the probes of the monitor to `module`, recording their
+    /// counts wherever `counters` put them. Returns the probes and the
+    /// size (in bytes) of their counters. Offsets start at 0 in the
+    /// region of the monitor, whatever other monitors are added.
+    fn instrument(
+        &self,
+        module: &mut Module,
+        counters: &Counters,
+        options: &Options,
+    ) -> walrus::Result<(Vec<Probe>, usize)>;
+
+    /// Decodes the counts of `probes` in a dump of the instrument
+    /// memory. `module` is the instrumented module the probes were
+    /// recorded for.
+    fn report(
+        &self,
+        module: &Module,
+        probes: &[Probe],
+        memory: &[u8],
+        options: &Options,
+    ) -> walrus::Result<String>;
+}
+
+/// The monitors shipped with this crate
+#[derive(Clone, Copy)]
+pub enum Builtin {
     Branch,
+    CallGraph,
+    Calls,
+    Heatmap,
     Hotness,
+    Indirect,
+    Loop,
+    Memory,
+    OpcodeMix,
+    Path,
+}
+
+impl Builtin {
+    pub const ALL: [Builtin; 10] = [
+        Builtin::Branch,
+        Builtin::CallGraph,
+        Builtin::Calls,
+        Builtin::Heatmap,
+        Builtin::Hotness,
+        Builtin::Indirect,
+        Builtin::Loop,
+        Builtin::Memory,
+        Builtin::OpcodeMix,
+        Builtin::Path,
+    ];
 }
 
-impl Monitor {
+impl Monitor for Builtin {
     fn name(&self) -> &str {
         match self {
-            Monitor::Branch => "branches",
-            Monitor::Hotness => "hotness",
+            Builtin::Branch => "branches",
+            Builtin::CallGraph => "callgraph",
+            Builtin::Calls => "calls",
+            Builtin::Heatmap => "heatmap",
+            Builtin::Hotness => "hotness",
+            Builtin::Indirect => "indirect",
+            Builtin::Loop => "loops",
+            Builtin::Memory => "memory",
+            Builtin::OpcodeMix => "opcodes",
+            Builtin::Path => "paths",
+        }
+    }
+
+    fn instrument(
+        &self,
+        module: &mut Module,
+        counters: &Counters,
+        options: &Options,
+    ) -> walrus::Result<(Vec<Probe>, usize)> {
+        let instrumented = match self {
+            Builtin::Branch => branch::instrument(
+                module,
+                counters,
+                options.granularity,
+                options.predictors,
+                options.selects,
+            )?,
+            Builtin::CallGraph => callgraph::instrument(module, counters, true)?,
+            Builtin::Calls => calls::instrument(module, counters, options.call_sites)?,
+            Builtin::Heatmap => heatmap::instrument(module, counters, options.bucket_size)?,
+            Builtin::Hotness => hotness::instrument(module, counters, options.granularity)?,
+            Builtin::Indirect => callgraph::instrument(module, counters, false)?,
+            Builtin::Loop => r#loop::instrument(module, counters)?,
+            Builtin::Memory => memory::instrument(module, counters, options.access)?,
+            Builtin::OpcodeMix => opcodes::instrument(module, counters)?,
+            Builtin::Path => path::instrument(module, counters),
+        };
+
+        Ok(instrumented)
+    }
+
+    fn report(
+        &self,
+        module: &Module,
+        probes: &[Probe],
+        memory: &[u8],
+        options: &Options,
+    ) -> walrus::Result<String> {
+        crate::report::builtin(*self, module, probes, memory, options)
+    }
+}
+
+/// Monitors available by name: the built-in ones and any registered
+/// on top of them. A monitor registered later replaces an earlier one
+/// of the same name.
+pub struct Registry {
+    monitors: Vec<Box<dyn Monitor>>,
+}
+
+impl Default for Registry {
+    fn default() -> Self {
+        Registry {
+            monitors: Builtin::ALL
+                .into_iter()
+                .map(|monitor| Box::new(monitor) as Box<dyn Monitor>)
+                .collect(),
+        }
+    }
+}
+
+impl Registry {
+    /// Adds a monitor, replacing any registered under the same name.
+    pub fn register(&mut self, monitor: Box<dyn Monitor>) {
+        self.monitors.retain(|other| other.name() != monitor.name());
+        self.monitors.push(monitor);
+    }
+
+    /// Looks up a monitor by name.
+    pub fn get(&self, name: &str) -> Option<&dyn Monitor> {
+        self.monitors
+            .iter()
+            .find(|monitor| monitor.name() == name)
+            .map(|monitor| monitor.as_ref())
+    }
+}
+
+/// How probes record their counts
+#[derive(Clone, Copy, Default)]
+pub enum Backend {
+    /// Inline load/add/store into a dedicated `instrument` memory
+    #[default]
+    Memory,
+    /// Inline load/add/store into pages appended to the module's own
+    /// memory, for engines without multi-memory support. The region
+    /// starts at the address held by the exported `instrument_base`
+    /// global and is hidden from `memory.size` and `memory.grow`.
+    SingleMemory,
+    /// Calls to the imported `instr.probe(i32 id)` for counters and
+    /// `instr.branch(i32 id, i32 outcome)` for branches, where `id`
+    /// is the counter offset divided by the count size and `outcome`
+    /// the index of the path taken
+    HostCall,
+    /// Inline increments of one mutable i64 global per counter,
+    /// exported as `__count_<id>` where `id` is the counter offset
+    /// divided by the count size. Needs neither memory nor imports
+    /// but only suits small modules.
+    Globals,
+}
+
+impl Backend {
+    pub fn name(&self) -> &str {
+        match self {
+            Backend::Memory => "memory",
+            Backend::SingleMemory => "single",
+            Backend::HostCall => "host",
+            Backend::Globals => "globals",
+        }
+    }
+}
+
+/// What counters are kept for
+#[derive(Clone, Copy, Default)]
+pub enum Granularity {
+    /// One counter per instruction (or branch outcome)
+    #[default]
+    Instruction,
+    /// One counter per straight-line basic block, shared by all of
+    /// its instructions. Blocks are split at `block`, `loop`, `if`,
+    /// branches, calls, `return` and `unreachable`. Only applies to
+    /// the hotness monitor.
+    BasicBlock,
+    /// Counters on the control flow edges outside a maximum spanning
+    /// tree only, all other counts are derived by flow conservation
+    Edge,
+}
+
+impl Granularity {
+    pub fn name(&self) -> &str {
+        match self {
+            Granularity::Instruction => "instr",
+            Granularity::BasicBlock => "block",
+            Granularity::Edge => "edge",
+        }
+    }
+}
+
+/// What the memory monitor records for every load and store
+#[derive(Clone, Copy, Default)]
+pub enum Access {
+    /// Number of accesses
+    #[default]
+    Count,
+    /// Number of accesses and the range of effective addresses
+    /// accessed. Only supported by the memory and single backends.
+    Range,
+    /// Number of accesses, and every access is reported to the
+    /// imported `instr.access(i32 id, i32 address, i32 size)` where
+    /// `id` is the counter offset divided by the count size
+    Trace,
+}
+
+/// How reports are written
+#[derive(Clone, Copy, Default)]
+pub enum Format {
+    /// Readable tables
+    #[default]
+    Text,
+    /// Graphviz DOT graph
+    Dot,
+    /// JSON document
+    Json,
+    /// Comma-separated values with a header row
+    Csv,
+}
+
+impl Format {
+    pub fn name(&self) -> &str {
+        match self {
+            Format::Text => "text",
+            Format::Dot => "dot",
+            Format::Json => "json",
+            Format::Csv => "csv",
+        }
+    }
+}
+
+/// Whether probes can be switched on and off at runtime. With a switch
+/// the module exports the i32 global `instrument_enabled` every probe
+/// checks before recording, `instrument_enable(i32)` setting it and
+/// `instrument_reset()` zeroing all counts. The host call backend
+/// imports `instr.reset()` to zero the counts kept by the host.
+#[derive(Clone, Copy, Default)]
+pub enum Switch {
+    /// Probes always record
+    #[default]
+    Absent,
+    /// Probes record until switched off
+    Enabled,
+    /// Probes record once switched on
+    Disabled,
+}
+
+/// Options shared by all monitors
+#[derive(Clone)]
+pub struct Options {
+    pub backend: Backend,
+    /// Only used by the hotness and branch monitors
+    pub granularity: Granularity,
+    /// Count every `call` instruction besides function entries. Only
+    /// used by the calls monitor.
+    pub call_sites: bool,
+    /// Keep the state of 1-bit and 2-bit predictors for every two-way
+    /// branch to count their mispredictions. Only used by the branch
+    /// monitor, requires the memory or single backend.
+    pub predictors: bool,
+    /// Count `select` as a two-way branch on its condition. Only used
+    /// by the branch monitor.
+    pub selects: bool,
+    /// Only used by the memory monitor
+    pub access: Access,
+    /// Size in bytes of the memory regions counted together, a power
+    /// of two. Only used by the heatmap monitor.
+    pub bucket_size: usize,
+    /// Only used by the call graph, heatmap and opcode mix reports,
+    /// the other reports are always text
+    pub format: Format,
+    /// Local functions probes are added to
+    pub functions: Filter,
+    /// Record only some executions of the probes, all if `None`.
+    /// Requires the memory or single backend.
+    pub sampling: Option<Sampling>,
+    pub switch: Switch,
+}
+
+impl Default for Options {
+    fn default() -> Options {
+        Options {
+            backend: Backend::default(),
+            granularity: Granularity::default(),
+            call_sites: false,
+            predictors: false,
+            selects: false,
+            access: Access::default(),
+            bucket_size: 4096,
+            format: Format::default(),
+            functions: Filter::default(),
+            sampling: None,
+            switch: Switch::default(),
+        }
+    }
+}
+
+/// Records only `burst` out of every `period` executions of every
+/// counter, counted down separately for each one. Every `period`-th
+/// execution is recorded with a burst of 1.
+#[derive(Clone, Copy)]
+pub struct Sampling {
+    pub period: u32,
+    pub burst: u32,
+}
+
+impl Sampling {
+    /// Checks the period is positive and the burst no longer.
+    pub fn new(period: u32, burst: u32) -> walrus::Result<Sampling> {
+        if burst == 0 || burst > period {
+            bail!(
+                "Invalid sampling of bursts of {} every {} executions",
+                burst,
+                period
+            );
+        }
+
+        Ok(Sampling { period, burst })
+    }
+
+    /// Estimates the number of executions from a sampled count.
+    pub fn scale(&self, count: u32) -> u32 {
+        (count as u64 * self.period as u64 / self.burst as u64).min(u32::MAX as u64) as u32
+    }
+}
+
+/// Describes what a single counter slot in the instrument
+/// memory is counting.
+#[derive(Debug)]
+pub struct Probe {
+    /// Where the count is kept. With basic block hotness all probes
+    /// of a block share one counter.
+    pub count: Count,
+    /// Function holding the probed instruction. Heatmap buckets and
+    /// opcode classes are shared by all functions and attributed to
+    /// the first one.
+    pub func: FunctionId,
+    /// Instruction sequences leading from the function body down to
+    /// the one holding the probed instruction
+    pub seq_path: Vec<InstrSeqId>,
+    /// Position of the probed instruction in its original
+    /// (uninstrumented) instruction sequence
+    pub index: usize,
+    pub opcode: String,
+    /// Which outcome of the probed instruction is counted. Always 0
+    /// except for branches where 0 counts a non-zero condition
+    /// (branch taken) and 1 a zero condition, for `br_table` where
+    /// it is the target index (the last one being the default) and
+    /// for loops where 0 counts entries and 1 iterations, for memory
+    /// address ranges where 0 counts accesses, 1 holds the highest end
+    /// and 2 the complement of the lowest address. Branches with
+    /// predictors keep the state of the 1-bit and 2-bit predictor in
+    /// outcomes 2 and 3, followed by the correct and mispredicted
+    /// counts of each (4 to 7). For paths
+    /// it is the path id (the number of paths of skipped functions),
+    /// for function entries and calls the index of the called function.
+    pub outcome: usize,
+    /// Basic blocks along the counted path, only set for paths
+    pub path: Vec<Span>,
+    /// Function entered or called, only set by the calls, call graph
+    /// and indirect call monitors. `None` for indirect calls through a table
+    /// slot that held no function when the module was instantiated.
+    pub callee: Option<FunctionId>,
+    /// Whether the branch jumps back to the start of a loop, only set
+    /// by the branch monitor
+    pub backward: bool,
+}
+
+impl Probe {
+    /// Nesting depth of the probed instruction (0 for the function body)
+    pub fn depth(&self) -> usize {
+        self.seq_path.len() - 1
+    }
+
+    /// Whether the probe stands for a function left alone by its
+    /// monitor, e.g. one with too many paths to profile
+    pub fn skipped(&self) -> bool {
+        self.opcode == SKIPPED
+    }
+}
+
+/// Range `start..end` of (original) instr positions in an
+/// instruction sequence
+#[derive(Debug, Clone)]
+pub struct Span {
+    pub seq: InstrSeqId,
+    pub start: usize,
+    pub end: usize,
+}
+
+/// Where the count of a probe is kept
+#[derive(Debug, Clone)]
+pub enum Count {
+    /// Byte offset of the counter in the instrument memory
+    Counter(usize),
+    /// Derived from other counters by flow conservation (edge
+    /// granularity): the sum of the counters at the byte offsets
+    /// times their factors
+    Derived(Vec<(usize, i64)>),
+    /// Byte offset of a slot holding a value rather than a count, such
+    /// as an address or predictor state, which sampling leaves alone
+    Value(usize),
+}
+
+impl Count {
+    /// Simplifies a sum of counters to a single counter if possible.
+    pub(crate) fn from_terms(terms: Vec<(usize, i64)>) -> Count {
+        match terms[..] {
+            [(offset, 1)] => Count::Counter(offset),
+            _ => Count::Derived(terms),
+        }
+    }
+
+    /// Moves the counters of a monitor into the region starting at
+    /// byte offset `region`.
+    fn relocate(&mut self, region: usize) {
+        match self {
+            Count::Counter(offset) | Count::Value(offset) => *offset += region,
+            Count::Derived(terms) => {
+                for (offset, _) in terms {
+                    *offset += region;
+                }
+            }
         }
     }
 }
 
-const MEMREGION: &str = "instrument";
-const MEMUNIT: usize = 64;
-const COUNTSIZE: usize = 4; // Size in bytes for storing a count
+pub(crate) const MEMREGION: &str = "instrument";
+pub(crate) const HOSTMODULE: &str = "instr";
+pub(crate) const BASEGLOBAL: &str = "instrument_base";
+pub(crate) const COUNTPREFIX: &str = "__count_";
+pub(crate) const SKIPPED: &str = "skipped"; // Opcode of probes of skipped functions
+pub(crate) const ENABLEDGLOBAL: &str = "instrument_enabled";
+pub(crate) const ENABLEFUNC: &str = "instrument_enable";
+pub(crate) const RESETFUNC: &str = "instrument_reset";
+const PAGESIZE: usize = 65536;
+const MAXPAGES: u32 = 65536;
+pub(crate) const COUNTSIZE: usize = 4; // Size in bytes for storing a count
 
 /// Adds monitor instrumentation bytecode to an existing
-/// WASM module.
-pub fn add_monitor(module: Module, monitor: Monitor, path: &Path) -> walrus::Result<()> {
-    let instrumented_module = match monitor {
-        Monitor::Branch => branch::instrument(module),
-        Monitor::Hotness => hotness::instrument(module),
-    };
+/// WASM module. Alongside the module a probe map describing
+/// every counter is written. Returns the probes of every monitor.
+pub fn add_monitor(
+    module: Module,
+    monitors: &[&dyn Monitor],
+    options: &Options,
+    path: &Path,
+) -> walrus::Result<Vec<Vec<Probe>>> {
+    let (instrumented_module, probes) = instrument(module, monitors, options)?;
+    let name = monitors_name(monitors);
+
+    write_probe_map(
+        &instrumented_module,
+        monitors,
+        &probes,
+        &name,
+        options,
+        path,
+    )?;
+    write_module(instrumented_module, &name, path)?;
+
+    Ok(probes)
+}
+
+/// Adds monitor instrumentation bytecode to a WASM module
+/// in memory and returns it with the probes of every monitor.
+/// Several monitors are added in a single pass:
+///     1.  Every monitor gets its own region of the counts, in the
+///         order they are given
+///     2.  Probe code of the monitors added before is hidden while a
+///         monitor is added, so it only probes the original instrs
+///     3.  Probe code of earlier monitors runs first
+///
+/// Local functions not selected by the function filter are left alone.
+pub fn instrument(
+    mut module: Module,
+    monitors: &[&dyn Monitor],
+    options: &Options,
+) -> walrus::Result<(Module, Vec<Vec<Probe>>)> {
+    // Add memory, globals or imports for storing counts
+    let counters = Counters::add(&mut module, options)?;
 
-    write_module(instrumented_module, &monitor.name(), path)
+    // Hide the functions left alone from all monitors
+    let detached = options.functions.detach(&mut module);
+
+    let mut probes = Vec::new();
+    let mut size = 0;
+    for monitor in monitors {
+        let hidden = compose::hide(&mut module);
+        let (mut monitor_probes, monitor_size) =
+            monitor.instrument(&mut module, &counters.region(size), options)?;
+        compose::restore(&mut module, hidden);
+
+        for probe in &mut monitor_probes {
+            probe.count.relocate(size);
+        }
+        probes.push(monitor_probes);
+        size += monitor_size;
+    }
+    detached.restore(&mut module);
+
+    // Size the memory region to fit all counts
+    let layout = Layout::plan(size, options.sampling.is_some())?;
+    counters.finish(&mut module, &layout)?;
+
+    Ok((module, probes))
+}
+
+/// Names a set of monitors for the files written by `add_monitor`,
+/// e.g. `hotness+branches`.
+fn monitors_name(monitors: &[&dyn Monitor]) -> String {
+    monitors
+        .iter()
+        .map(|monitor| monitor.name())
+        .collect::<Vec<_>>()
+        .join("+")
+}
+
+/// Returns the number of bytes taken up by the counters of the probes.
+pub fn counts_size(probes: &[Probe]) -> usize {
+    probes
+        .iter()
+        .flat_map(|probe| match &probe.count {
+            Count::Counter(offset) | Count::Value(offset) => vec![*offset],
+            Count::Derived(terms) => terms.iter().map(|(offset, _)| *offset).collect(),
+        })
+        .map(|offset| offset + COUNTSIZE)
+        .max()
+        .unwrap_or(0)
+}
+
+/// Space taken up by the counters of an instrumented module
+pub(crate) struct Layout {
+    /// Exact number of bytes required to hold all counts
+    pub(crate) size: usize,
+    /// Number of WASM pages required to hold all counts (and the
+    /// countdowns of sampled counters)
+    pub(crate) pages: u32,
+}
+
+impl Layout {
+    /// Plans the layout for `size` bytes of counters, followed by as
+    /// many bytes of countdowns if `sampled`. Fails if they do not fit
+    /// into a 32-bit memory.
+    pub(crate) fn plan(size: usize, sampled: bool) -> walrus::Result<Layout> {
+        let pages = (size * (1 + sampled as usize)).div_ceil(PAGESIZE);
+        if pages > MAXPAGES as usize {
+            bail!(
+                "Counts require {} bytes ({} pages) but a memory holds at most {} pages",
+                size,
+                pages,
+                MAXPAGES
+            );
+        }
+
+        Ok(Layout {
+            size,
+            pages: pages as u32,
+        })
+    }
 }
 
 /// Writes the WASM module to the given path adding
 /// monitor name to the file name.
 fn write_module(mut module: Module, monitor_name: &str, path: &Path) -> walrus::Result<()> {
-    let file_stem = path.file_stem().unwrap().to_str().unwrap();
     let extension = path.extension().unwrap().to_str().unwrap();
+    module.emit_wasm_file(output_path(path, monitor_name, extension))
+}
+
+/// Writes a JSON probe map for the WASM module at the given
+/// path adding monitor name to the file name.
+fn write_probe_map(
+    module: &Module,
+    monitors: &[&dyn Monitor],
+    probes: &[Vec<Probe>],
+    monitor_name: &str,
+    options: &Options,
+    path: &Path,
+) -> walrus::Result<()> {
+    let probes: Vec<_> = monitors
+        .iter()
+        .zip(probes)
+        .flat_map(|(monitor, probes)| probes.iter().map(move |probe| (monitor.name(), probe)))
+        .map(|(name, probe)| {
+            json!({
+                "monitor": name,
+                "offset": match probe.count {
+                    Count::Counter(offset) | Count::Value(offset) => Some(offset),
+                    Count::Derived(_) => None,
+                },
+                "derived": match &probe.count {
+                    Count::Counter(_) | Count::Value(_) => &[][..],
+                    Count::Derived(terms) => &terms[..],
+                },
+                "func_index": probe.func.index(),
+                "func_name": module.funcs.get(probe.func).name,
+                "seq_path": probe.seq_path.iter().map(|id| id.index()).collect::<Vec<_>>(),
+                "instr_index": probe.index,
+                "opcode": probe.opcode,
+                "outcome": probe.outcome,
+                "path": probe
+                    .path
+                    .iter()
+                    .map(|span| [span.seq.index(), span.start, span.end])
+                    .collect::<Vec<_>>(),
+                "callee": probe.callee.map(|callee| callee.index()),
+            })
+        })
+        .collect();
+    let map = json!({
+        "monitor": monitor_name,
+        "backend": options.backend.name(),
+        "sampling": options.sampling.map(|sampling| json!({
+            "period": sampling.period,
+            "burst": sampling.burst,
+        })),
+        "memory": MEMREGION,
+        "count_size": COUNTSIZE,
+        "probes": probes,
+    });
+
+    fs::write(
+        output_path(path, monitor_name, "map.json"),
+        serde_json::to_string_pretty(&map)?,
+    )?;
+
+    Ok(())
+}
+
+/// Derives the path of an output file from the input module path
+/// by adding the monitor name to the file stem.
+fn output_path(path: &Path, monitor_name: &str, extension: &str) -> PathBuf {
+    let file_stem = path.file_stem().unwrap().to_str().unwrap();
     let new_file_stem = format!("{}-{}", file_stem, monitor_name);
     let new_file_name = PathBuf::from(format!("{}.{}", new_file_stem, extension));
 
-    module.emit_wasm_file(path.with_file_name(new_file_name))
+    path.with_file_name(new_file_name)
+}
+
+/// Returns a readable name for an instruction. Binary and unary
+/// operators are named after their specific operation.
+pub fn opcode(instr: &Instr) -> String {
+    let name = match instr {
+        Instr::Block(_) => "block",
+        Instr::Loop(_) => "loop",
+        Instr::IfElse(_) => "if",
+        Instr::Br(_) => "br",
+        Instr::BrIf(_) => "br_if",
+        Instr::BrTable(_) => "br_table",
+        Instr::Call(_) => "call",
+        Instr::CallIndirect(_) => "call_indirect",
+        Instr::LocalGet(_) => "local.get",
+        Instr::LocalSet(_) => "local.set",
+        Instr::LocalTee(_) => "local.tee",
+        Instr::GlobalGet(_) => "global.get",
+        Instr::GlobalSet(_) => "global.set",
+        Instr::Const(_) => "const",
+        Instr::Binop(binop) => return format!("{:?}", binop.op),
+        Instr::Unop(unop) => return format!("{:?}", unop.op),
+        Instr::Select(_) => "select",
+        Instr::Unreachable(_) => "unreachable",
+        Instr::Drop(_) => "drop",
+        Instr::Return(_) => "return",
+        Instr::MemorySize(_) => "memory.size",
+        Instr::MemoryGrow(_) => "memory.grow",
+        Instr::MemoryInit(_) => "memory.init",
+        Instr::DataDrop(_) => "data.drop",
+        Instr::MemoryCopy(_) => "memory.copy",
+        Instr::MemoryFill(_) => "memory.fill",
+        Instr::Load(_) => "load",
+        Instr::Store(_) => "store",
+        Instr::AtomicRmw(_) => "atomic.rmw",
+        Instr::Cmpxchg(_) => "atomic.cmpxchg",
+        Instr::AtomicNotify(_) => "atomic.notify",
+        Instr::AtomicWait(_) => "atomic.wait",
+        Instr::AtomicFence(_) => "atomic.fence",
+        Instr::TableGet(_) => "table.get",
+        Instr::TableSet(_) => "table.set",
+        Instr::TableGrow(_) => "table.grow",
+        Instr::TableSize(_) => "table.size",
+        Instr::TableFill(_) => "table.fill",
+        Instr::RefNull(_) => "ref.null",
+        Instr::RefIsNull(_) => "ref.is_null",
+        Instr::RefFunc(_) => "ref.func",
+        Instr::V128Bitselect(_) => "v128.bitselect",
+        Instr::I8x16Swizzle(_) => "i8x16.swizzle",
+        Instr::I8x16Shuffle(_) => "i8x16.shuffle",
+        Instr::LoadSimd(_) => "load_simd",
+        Instr::TableInit(_) => "table.init",
+        Instr::ElemDrop(_) => "elem.drop",
+        Instr::TableCopy(_) => "table.copy",
+    };
+
+    name.to_string()
 }
@@ -1,291 +1,336 @@
-use std::cmp::max;
-
+use anyhow::bail;
 use walrus::{
-    ir::{
-        BinaryOp, Binop, Const, Instr, InstrSeqId, Load, LoadKind, MemArg, Store, StoreKind, Value,
-    },
-    ExportItem, LocalFunction, LocalId, Memory, MemoryId, Module, ModuleTypes, ValType,
+    ir::{BinaryOp, Instr, InstrSeqId, UnaryOp, Value},
+    FunctionId, InstrSeqBuilder, LocalFunction, LocalId, Module, ValType,
 };
 
-use crate::monitor::MEMUNIT;
-
-use super::{COUNTSIZE, MEMREGION};
+use super::{
+    counters::Counters,
+    edges::EdgeProfile,
+    opcode,
+    sites::{instrument_sites, Sites},
+    Count, Granularity, Probe, COUNTSIZE,
+};
 
-// Struct to store info on insertion locations for an instruction sequence.
-// Note that blocks can be indefinitely nested.
-#[derive(Debug)]
-struct ProbeInsertLocs {
-    id: InstrSeqId,
+/// Counters of a two-way branch with predictors: the two outcomes,
+/// the state of the 1-bit and the 2-bit predictor and the correct
+/// and mispredicted counts of each
+const PREDICTOR_SLOTS: usize = 8;
 
-    // (instr position, no. of paths, nested ProbeInsertLocs)
-    positions: Vec<(usize, usize, Option<ProbeInsertLocs>)>,
+/// Branches probed by the branch monitor
+struct BranchSites {
+    /// Local to save top of stack
+    local_id: LocalId,
+    /// Local for the predictor state, if predictors are simulated
+    state_id: Option<LocalId>,
+    /// Whether `select` counts as a branch
+    selects: bool,
 }
 
 /// Adds branch instrumentation logic to a module.
-///     1.  Add a linear memory to keep track of counts
-///     2.  If a local function has `n` instructions `n * SIZE` bytes will
-///         be reserved for storing counts for each instruction.
-///     3.  Maintain starting offset `foffset` in the memory segment for each
-///         function se we can calculate the memory offset for an
-///         instruction as `foffset + ioffset` to increment count.
-///     4.  For each branching instr copies top of stack into a local.
-///         Then uses the top of stack in an if condition to increment
-///         count in memory and then restores the top of stack from local.
-///     5.  TODO: Figure out how to print the output at the end.
-pub fn instrument(mut module: Module) -> Module {
-    // Add linear memory for storing counts
-    // XXX: Might need to initialize to 0
-    let mem_id = module.memories.add_local(false, 1, None);
-    module.exports.add(MEMREGION, ExportItem::Memory(mem_id));
+///     1.  Counts are kept wherever the provided `Counters` put them
+///     2.  Branches are instrumented by `instrument_sites`, every
+///         branch gets a counter per path.
+///     3.  For each branching instr copies top of stack into a local.
+///         Then uses the top of stack to pick the counter of the path
+///         taken and increments it. For `if`/`br_if` the counter index
+///         is `eqz` of the condition, a `br_table` uses its selector
+///         clamped to the default target (one counter per target).
+///         With `selects` a `select` counts as a two-way branch on
+///         its condition like `br_if`.
+///     4.  With `predictors` every `if` and `br_if` (and `select`
+///         with `selects`) also updates the state of a 1-bit predictor
+///         (predicting the last outcome) and a 2-bit saturating counter
+///         kept next to its counters, and counts whether each predicted
+///         the outcome correctly.
+///     5.  Record a `Probe` for every counter so the captured memory
+///         can be decoded afterwards.
+///
+/// With `Granularity::Edge` only some control flow edges get a counter
+/// and the outcomes of branches are derived from them.
+///
+/// Returns the probes and the size (in bytes) of their counters.
+/// Fails for predictors or selects with edge granularity or for
+/// predictors with a backend that does not keep counts in memory.
+pub fn instrument(
+    module: &mut Module,
+    counters: &Counters,
+    granularity: Granularity,
+    predictors: bool,
+    selects: bool,
+) -> walrus::Result<(Vec<Probe>, usize)> {
+    if predictors && matches!(granularity, Granularity::Edge) {
+        bail!("Branch predictors cannot be simulated with edge granularity");
+    }
+    if selects && matches!(granularity, Granularity::Edge) {
+        bail!("Selects are not control flow edges and cannot be counted with edge granularity");
+    }
 
-    // Create local var to save top of stack
+    // Create local vars to save top of stack and predictor state
     let local_id = module.locals.add(ValType::I32);
+    let state_id = module.locals.add(ValType::I32);
 
-    // Iterate on local functions
-    let mut foffsets: Vec<usize> = Vec::new();
-    let mut curr_foffset = 0;
-    module.funcs.iter_local_mut().for_each(|(_, func)| {
-        // Add function offset
-        foffsets.push(curr_foffset);
+    if let Granularity::Edge = granularity {
+        return Ok(instrument_edges(module, counters, local_id));
+    }
 
-        curr_foffset += instrument_func(&mut module.types, func, curr_foffset, mem_id, local_id);
-    });
+    let sites = BranchSites {
+        local_id,
+        state_id: predictors.then_some(state_id),
+        selects,
+    };
+    instrument_sites(module, counters, &sites)
+}
 
-    // Update size of memory region
-    let mem_region: &mut Memory = module.memories.get_mut(mem_id);
-    let mem_size = (max(1, curr_foffset / MEMUNIT)) as u32;
-    mem_region.initial = mem_size;
-    mem_region.maximum = Some(mem_size);
+/// Counts edges of the control flow graph of every local function and
+/// records the outcomes of branches derived from them.
+fn instrument_edges(
+    module: &mut Module,
+    counters: &Counters,
+    local_id: LocalId,
+) -> (Vec<Probe>, usize) {
+    let mut curr_foffset = 0;
+    let mut probes: Vec<Probe> = Vec::new();
+    for (id, func) in module.funcs.iter_local_mut() {
+        let profile = EdgeProfile::new(func, curr_foffset);
+        let entry = func.entry_block();
+        record_edge_probes(id, func, entry, &profile, &[entry], &mut probes);
+        profile.insert_counters(func, counters, local_id);
 
-    module
+        curr_foffset += profile.size();
+    }
+
+    (probes, curr_foffset)
 }
 
-/// Instrument a local function and return size (in bytes)
-/// of memory it will require to capture its instrumentation data
-fn instrument_func(
-    types: &mut ModuleTypes,
-    func: &mut LocalFunction,
-    foffset: usize,
-    mem_id: MemoryId,
-    local_id: LocalId,
-) -> usize {
-    // Get insert locations for probe insertion
-    let probe_insert_locs = get_probe_insert_locs(func, func.entry_block());
+impl Sites for BranchSites {
+    fn select(
+        &self,
+        _func: &LocalFunction,
+        _seq_path: &[InstrSeqId],
+        _index: usize,
+        instr: &Instr,
+    ) -> Option<usize> {
+        let npaths = match instr {
+            // A br_table has a counter per target plus the default one
+            Instr::BrTable(table) => return Some(table.blocks.len() + 1),
+            Instr::IfElse(_) | Instr::BrIf(_) => 2,
+            // The condition is on top of the stack like for br_if
+            Instr::Select(_) if self.selects => 2,
+            _ => return None,
+        };
 
-    // println!("{:#?}", probe_insert_locs);
+        // Predictors are kept next to the paths of two-way branches
+        match self.state_id {
+            Some(_) => Some(PREDICTOR_SLOTS),
+            None => Some(npaths),
+        }
+    }
 
-    // Insert probes (counting instructions) at the locations
-    let insert_count = insert_probes(
-        types,
-        func,
-        &probe_insert_locs,
-        &foffset,
-        &mem_id,
-        &local_id,
-    );
+    fn emit(
+        &self,
+        instr_builder: &mut InstrSeqBuilder,
+        pos: usize,
+        instr: &Instr,
+        offset: usize,
+        counters: &Counters,
+    ) -> walrus::Result<usize> {
+        let mut i = pos;
 
-    insert_count * COUNTSIZE
-}
+        // Save top of stack to local var
+        instr_builder.local_tee_at(i, self.local_id);
+        i += 1;
 
-fn get_probe_insert_locs(func: &LocalFunction, instr_seq_id: InstrSeqId) -> ProbeInsertLocs {
-    let mut insert_locs = ProbeInsertLocs {
-        id: instr_seq_id,
-        positions: vec![],
-    };
+        // Restore top of stack from local var
+        instr_builder.local_get_at(i, self.local_id);
+        i += 1;
 
-    func.block(instr_seq_id)
-        .iter()
-        .enumerate()
-        .for_each(|(i, (instr, _))| {
-            // Recurse for nexted blocks
-            match instr {
-                Instr::Block(block) => {
-                    let block_insert_locs = get_probe_insert_locs(func, block.seq);
-                    insert_locs.positions.push((i, 0, Some(block_insert_locs)));
-                }
-                Instr::Loop(block) => {
-                    let block_insert_locs = get_probe_insert_locs(func, block.seq);
-                    insert_locs.positions.push((i, 0, Some(block_insert_locs)));
-                }
-                Instr::IfElse(block) => {
-                    let if_block_insert_locs = get_probe_insert_locs(func, block.consequent);
-                    let else_block_insert_locs = get_probe_insert_locs(func, block.alternative);
-                    insert_locs
-                        .positions
-                        .push((i, 0, Some(if_block_insert_locs)));
-                    insert_locs
-                        .positions
-                        .push((i, 0, Some(else_block_insert_locs)));
+        if let Instr::BrTable(table) = instr {
+            // Clamp the selector to the default target to get
+            // the index of the counter to increment
+            let default = table.blocks.len() as i32;
+            instr_builder
+                .const_at(i, Value::I32(default))
+                .local_get_at(i + 1, self.local_id)
+                .const_at(i + 2, Value::I32(default))
+                .binop_at(i + 3, BinaryOp::I32LtU)
+                .select_at(i + 4, None);
+            i += 5;
+        } else {
+            // If 2 paths then the counter index is 0 for a
+            // non-zero condition and 1 otherwise
+            instr_builder.unop_at(i, UnaryOp::I32Eqz);
+            i += 1;
+        }
+
+        // Insert counting instrs
+        i += counters.insert_indexed_increment(instr_builder, i, offset);
 
-                    // We also need to evaluate the operand before the if block
-                    insert_locs.positions.push((i, 2, None));
-                }
-                Instr::BrTable(table) => {
-                    // We need to evaluate the operand before the br_table instr
-                    insert_locs
-                        .positions
-                        .push((i, table.blocks.len() + 1, None));
-                }
-                Instr::BrIf(_) => {
-                    insert_locs.positions.push((i, 2, None));
-                }
-                Instr::Call(_)
-                | Instr::CallIndirect(_)
-                | Instr::LocalGet(_)
-                | Instr::LocalSet(_)
-                | Instr::LocalTee(_)
-                | Instr::GlobalGet(_)
-                | Instr::GlobalSet(_)
-                | Instr::Const(_)
-                | Instr::Binop(_)
-                | Instr::Unop(_)
-                | Instr::Select(_)
-                | Instr::Unreachable(_)
-                | Instr::Br(_)
-                | Instr::Drop(_)
-                | Instr::Return(_)
-                | Instr::MemorySize(_)
-                | Instr::MemoryGrow(_)
-                | Instr::MemoryInit(_)
-                | Instr::DataDrop(_)
-                | Instr::MemoryCopy(_)
-                | Instr::MemoryFill(_)
-                | Instr::Load(_)
-                | Instr::Store(_)
-                | Instr::AtomicRmw(_)
-                | Instr::Cmpxchg(_)
-                | Instr::AtomicNotify(_)
-                | Instr::AtomicWait(_)
-                | Instr::AtomicFence(_)
-                | Instr::TableGet(_)
-                | Instr::TableSet(_)
-                | Instr::TableGrow(_)
-                | Instr::TableSize(_)
-                | Instr::TableFill(_)
-                | Instr::RefNull(_)
-                | Instr::RefIsNull(_)
-                | Instr::RefFunc(_)
-                | Instr::V128Bitselect(_)
-                | Instr::I8x16Swizzle(_)
-                | Instr::I8x16Shuffle(_)
-                | Instr::LoadSimd(_)
-                | Instr::TableInit(_)
-                | Instr::ElemDrop(_)
-                | Instr::TableCopy(_) => {
-                    // do nothing
-                }
+        match (self.state_id, instr) {
+            (_, Instr::BrTable(_)) | (None, _) => {}
+            (Some(state_id), _) => {
+                i +=
+                    insert_predictors(instr_builder, i, offset, counters, self.local_id, state_id)?;
             }
-        });
+        }
 
-    insert_locs
-}
+        Ok(i - pos)
+    }
 
-/// Insert probes at the provided insert locations
-/// Recursively does it for all nested blocks and returns
-/// total count of inserted probes
-fn insert_probes(
-    types: &mut ModuleTypes,
-    func: &mut LocalFunction,
-    insert_locs: &ProbeInsertLocs,
-    foffset: &usize,
-    mem_id: &MemoryId,
-    local_id: &LocalId,
-) -> usize {
-    let mut inserts_so_far: usize = 0;
-    let mut probe_count = 0;
-    for (_, (pos_orig, npaths, block_insert_locs_option)) in
-        insert_locs.positions.iter().enumerate()
-    {
-        let ioffset = foffset + (probe_count * COUNTSIZE); // offset for storing count
+    fn describe(
+        &self,
+        func: &LocalFunction,
+        seq_path: &[InstrSeqId],
+        instr: &Instr,
+        probe: &mut Probe,
+    ) {
+        probe.backward = is_backward(func, seq_path, instr);
 
-        match block_insert_locs_option {
-            Some(block_insert_locs) => {
-                let insert_count =
-                    insert_probes(types, func, block_insert_locs, &ioffset, mem_id, local_id);
-                probe_count += insert_count;
-            }
-            None => {
-                let func_builder = func.builder_mut();
-                let mut instr_builder = func_builder.instr_seq(insert_locs.id);
-                let mut i = pos_orig + inserts_so_far;
+        // The state of the predictors is no count
+        let state = self.state_id.is_some() && !matches!(instr, Instr::BrTable(_));
+        if let (Count::Counter(offset), 2 | 3, true) = (&probe.count, probe.outcome, state) {
+            probe.count = Count::Value(*offset);
+        }
+    }
+}
 
-                // Save top of stack to local var
-                instr_builder.local_tee_at(i, *local_id);
-                i += 1;
+/// Inserts instrs updating the predictors of a two-way branch whose
+/// condition is held by `local_id`, with counters starting at `offset`.
+/// The 1-bit predictor predicts the last outcome. The 2-bit one counts
+/// up to 3 on a zero condition and down to 0 otherwise, predicting a
+/// zero condition from 2 on. Both initially predict a non-zero one.
+/// Returns the number of inserted instrs.
+fn insert_predictors(
+    instr_builder: &mut InstrSeqBuilder,
+    pos: usize,
+    offset: usize,
+    counters: &Counters,
+    local_id: LocalId,
+    state_id: LocalId,
+) -> walrus::Result<usize> {
+    let mut i = pos;
+    let (state1, state2) = (offset + 2 * COUNTSIZE, offset + 3 * COUNTSIZE);
 
-                // Restore top of stack from local var
-                instr_builder.local_get_at(i, *local_id);
-                i += 1;
+    // Count whether the last outcome was the same, then keep this one
+    instr_builder
+        .local_get_at(i, local_id)
+        .unop_at(i + 1, UnaryOp::I32Eqz);
+    i += 2;
+    i += counters.insert_load(instr_builder, i, state1)?;
+    instr_builder.binop_at(i, BinaryOp::I32Ne);
+    i += 1;
+    i += counters.insert_indexed_increment(instr_builder, i, offset + 4 * COUNTSIZE);
+    instr_builder
+        .local_get_at(i, local_id)
+        .unop_at(i + 1, UnaryOp::I32Eqz);
+    i += 2;
+    i += counters.insert_store(instr_builder, i, state1)?;
 
-                // If 2 paths then set load/store index using if block
-                // TODO: Treat br_table specially (for experiment its fine
-                // as even for br table it can be a single if else block)
-                let ty = types.add(&[], &[ValType::I32, ValType::I32]);
-                instr_builder.if_else_at(
-                    i,
-                    ty,
-                    // Need two return 2 consts: one for load and one for store
-                    |then| {
-                        then.i32_const(ioffset as i32).i32_const(ioffset as i32);
-                    },
-                    |else_| {
-                        else_
-                            .i32_const((ioffset + COUNTSIZE) as i32)
-                            .i32_const((ioffset + COUNTSIZE) as i32);
-                    },
-                );
-                i += 1;
+    // Count whether the upper bit of the saturating counter predicted
+    // the outcome
+    instr_builder
+        .local_get_at(i, local_id)
+        .unop_at(i + 1, UnaryOp::I32Eqz);
+    i += 2;
+    i += counters.insert_load(instr_builder, i, state2)?;
+    instr_builder
+        .local_tee_at(i, state_id)
+        .const_at(i + 1, Value::I32(1))
+        .binop_at(i + 2, BinaryOp::I32ShrU)
+        .binop_at(i + 3, BinaryOp::I32Ne);
+    i += 4;
+    i += counters.insert_indexed_increment(instr_builder, i, offset + 6 * COUNTSIZE);
 
-                // Insert load instr
-                instr_builder.instr_at(
-                    i,
-                    Instr::Load(Load {
-                        memory: *mem_id,
-                        kind: LoadKind::I32 { atomic: false },
-                        arg: MemArg {
-                            align: COUNTSIZE as u32,
-                            offset: 0,
-                        },
-                    }),
-                );
-                i += 1;
+    // Pick the counter moved up (unless 3) or down (unless 0) by the
+    // outcome
+    instr_builder
+        .local_get_at(i, state_id)
+        .local_get_at(i + 1, state_id)
+        .const_at(i + 2, Value::I32(3))
+        .binop_at(i + 3, BinaryOp::I32Ne)
+        .binop_at(i + 4, BinaryOp::I32Add)
+        .local_get_at(i + 5, state_id)
+        .local_get_at(i + 6, state_id)
+        .const_at(i + 7, Value::I32(0))
+        .binop_at(i + 8, BinaryOp::I32Ne)
+        .binop_at(i + 9, BinaryOp::I32Sub)
+        .local_get_at(i + 10, local_id)
+        .unop_at(i + 11, UnaryOp::I32Eqz)
+        .select_at(i + 12, None);
+    i += 13;
+    i += counters.insert_store(instr_builder, i, state2)?;
 
-                // Insert increment count const
-                let incr_count = Const {
-                    value: Value::I32(1),
-                };
-                let i32_const_incr_count: Instr = Instr::Const(incr_count);
-                instr_builder.instr_at(i, i32_const_incr_count);
-                i += 1;
+    Ok(i - pos)
+}
 
-                // Insert add instr
-                instr_builder.instr_at(
-                    i,
-                    Instr::Binop(Binop {
-                        op: BinaryOp::I32Add,
-                    }),
-                );
-                i += 1;
+/// Whether a branch instr jumps back to the start of a loop. The loop
+/// encloses the branch, so it is found in the parent of its body.
+fn is_backward(func: &LocalFunction, seq_path: &[InstrSeqId], instr: &Instr) -> bool {
+    let target = match instr {
+        Instr::BrIf(br) => br.block,
+        _ => return false,
+    };
 
-                // Insert store instr
-                instr_builder.instr_at(
-                    i,
-                    Instr::Store(Store {
-                        memory: *mem_id,
-                        kind: StoreKind::I32 { atomic: false },
-                        arg: MemArg {
-                            align: COUNTSIZE as u32,
-                            offset: 0,
-                        },
+    seq_path.windows(2).any(|pair| {
+        pair[1] == target
+            && func
+                .block(pair[0])
+                .iter()
+                .any(|(instr, _)| matches!(instr, Instr::Loop(block) if block.seq == target))
+    })
+}
+
+/// Record a probe for every path of every branch in an instruction
+/// sequence and its nested blocks with its count derived from the edge
+/// profile
+fn record_edge_probes(
+    func_id: FunctionId,
+    func: &LocalFunction,
+    seq_id: InstrSeqId,
+    profile: &EdgeProfile,
+    seq_path: &[InstrSeqId],
+    probes: &mut Vec<Probe>,
+) {
+    for (pos, (instr, _)) in func.block(seq_id).iter().enumerate() {
+        if let Instr::IfElse(_) | Instr::BrIf(_) | Instr::BrTable(_) = instr {
+            // The edges leaving the branch's block are its paths
+            let backward = is_backward(func, seq_path, instr);
+            let opcode = opcode(instr);
+            let counts = profile.outcome_counts(seq_id, pos);
+            probes.extend(
+                counts
+                    .into_iter()
+                    .enumerate()
+                    .map(|(outcome, count)| Probe {
+                        count,
+                        func: func_id,
+                        seq_path: seq_path.to_vec(),
+                        index: pos,
+                        opcode: opcode.clone(),
+                        outcome,
+                        path: vec![],
+                        callee: None,
+                        backward,
                     }),
-                );
-                i += 1;
+            );
+        }
 
-                inserts_so_far = i - pos_orig;
-                probe_count += npaths;
-            }
+        // Recurse for nested blocks
+        let nested = match instr {
+            Instr::Block(block) => vec![block.seq],
+            Instr::Loop(block) => vec![block.seq],
+            Instr::IfElse(block) => vec![block.consequent, block.alternative],
+            _ => vec![],
+        };
+        for seq in nested {
+            record_edge_probes(
+                func_id,
+                func,
+                seq,
+                profile,
+                &[seq_path, &[seq]].concat(),
+                probes,
+            );
         }
     }
-
-    probe_count
 }
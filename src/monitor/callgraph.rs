@@ -0,0 +1,185 @@
+use walrus::{
+    ir::{BinaryOp, Instr, InstrSeqId, Value},
+    ElementKind, FunctionId, GlobalKind, InitExpr, InstrSeqBuilder, LocalFunction, LocalId, Module,
+    TableId, ValType,
+};
+
+use super::{
+    counters::Counters,
+    sites::{instrument_sites, Sites},
+    Probe,
+};
+
+/// Most table slots counted separately at every `call_indirect`
+const MAXSLOTS: usize = 1024;
+
+/// Calls probed by the call graph monitor
+struct CallSites {
+    /// Local to copy table indices
+    local_id: LocalId,
+    /// Function held by every slot of every table
+    tables: Vec<(TableId, Vec<Option<FunctionId>>)>,
+    /// Whether `call` instrs are probed too
+    direct: bool,
+}
+
+/// Adds call graph instrumentation logic to a module.
+///     1.  Counts are kept wherever the provided `Counters` put them
+///     2.  Calls are instrumented by `instrument_sites`. Every `call`
+///         gets a counter for the edge to its callee, unless only
+///         indirect calls are counted (`direct` unset).
+///     3.  Every `call_indirect` gets a counter per slot of the table
+///         it calls through, for at most `MAXSLOTS` slots, plus one
+///         for the rest: slots past these, slots added by `table.grow`
+///         and out of range indices (which trap). The table index is
+///         copied before the call to pick the counter.
+///     4.  Record a `Probe` for every counter with the function it
+///         calls so the captured memory can be decoded afterwards.
+///
+/// Indirect callees are taken from the active element segments, so
+/// tables modified at runtime are not accounted for.
+///
+/// Returns the probes and the size (in bytes) of their counters.
+pub fn instrument(
+    module: &mut Module,
+    counters: &Counters,
+    direct: bool,
+) -> walrus::Result<(Vec<Probe>, usize)> {
+    // Create a local var to copy table indices
+    let local_id = module.locals.add(ValType::I32);
+
+    // Resolve the slots of all tables up front
+    let tables: Vec<(TableId, Vec<Option<FunctionId>>)> = module
+        .tables
+        .iter()
+        .map(|table| (table.id(), table_targets(module, table.id())))
+        .collect();
+
+    let sites = CallSites {
+        local_id,
+        tables,
+        direct,
+    };
+    instrument_sites(module, counters, &sites)
+}
+
+/// Returns the function held by every slot of a table right after
+/// instantiation, according to its active element segments.
+/// Segments with an offset that is not known statically are skipped.
+pub(crate) fn table_targets(module: &Module, table_id: TableId) -> Vec<Option<FunctionId>> {
+    let table = module.tables.get(table_id);
+    let mut targets = vec![None; table.initial as usize];
+    for element in module.elements.iter() {
+        let offset = match element.kind {
+            ElementKind::Active { table, offset } if table == table_id => offset,
+            _ => continue,
+        };
+        let offset = match offset {
+            InitExpr::Value(Value::I32(offset)) => offset,
+            InitExpr::Global(global) => match module.globals.get(global).kind {
+                GlobalKind::Local(InitExpr::Value(Value::I32(offset))) => offset,
+                _ => continue,
+            },
+            _ => continue,
+        };
+
+        for (i, member) in element.members.iter().enumerate() {
+            if let Some(target) = targets.get_mut(offset as u32 as usize + i) {
+                *target = *member;
+            }
+        }
+    }
+
+    targets
+}
+
+impl CallSites {
+    /// Returns the function held by every slot of a table
+    fn targets(&self, table_id: TableId) -> &[Option<FunctionId>] {
+        let (_, targets) = self
+            .tables
+            .iter()
+            .find(|(table, _)| *table == table_id)
+            .unwrap();
+
+        targets
+    }
+
+    /// Returns the number of slots of a table counted separately
+    fn slots(&self, table_id: TableId) -> usize {
+        self.targets(table_id).len().min(MAXSLOTS)
+    }
+}
+
+impl Sites for CallSites {
+    fn select(
+        &self,
+        _func: &LocalFunction,
+        _seq_path: &[InstrSeqId],
+        _index: usize,
+        instr: &Instr,
+    ) -> Option<usize> {
+        match instr {
+            Instr::Call(_) if self.direct => Some(1),
+            // One counter per table slot, the last one for the rest
+            Instr::CallIndirect(call) => Some(self.slots(call.table) + 1),
+            _ => None,
+        }
+    }
+
+    fn emit(
+        &self,
+        instr_builder: &mut InstrSeqBuilder,
+        pos: usize,
+        instr: &Instr,
+        offset: usize,
+        counters: &Counters,
+    ) -> walrus::Result<usize> {
+        let mut i = pos;
+        if let Instr::CallIndirect(call) = instr {
+            // Save the table index to local var and restore it
+            instr_builder
+                .local_tee_at(i, self.local_id)
+                .local_get_at(i + 1, self.local_id);
+            i += 2;
+
+            // Clamp the table index to the counter of the rest to get
+            // the index of the counter to increment
+            let last = self.slots(call.table) as i32;
+            instr_builder
+                .const_at(i, Value::I32(last))
+                .local_get_at(i + 1, self.local_id)
+                .const_at(i + 2, Value::I32(last))
+                .binop_at(i + 3, BinaryOp::I32LtU)
+                .select_at(i + 4, None);
+            i += 5;
+
+            // Insert counting instrs
+            i += counters.insert_indexed_increment(instr_builder, i, offset);
+        } else {
+            // Insert counting instrs
+            i += counters.insert_increment(instr_builder, i, offset);
+        }
+
+        Ok(i - pos)
+    }
+
+    fn describe(
+        &self,
+        _func: &LocalFunction,
+        _seq_path: &[InstrSeqId],
+        instr: &Instr,
+        probe: &mut Probe,
+    ) {
+        match instr {
+            Instr::Call(call) => {
+                probe.outcome = call.func.index();
+                probe.callee = Some(call.func);
+            }
+            Instr::CallIndirect(call) if probe.outcome < self.slots(call.table) => {
+                probe.callee = self.targets(call.table)[probe.outcome];
+            }
+            _ => {}
+        }
+    }
+}
@@ -0,0 +1,108 @@
+use walrus::{
+    ir::{Instr, InstrSeqId},
+    InstrSeqBuilder, LocalFunction, Module,
+};
+
+use super::{
+    counters::Counters,
+    sites::{instrument_func_sites, Sites},
+    Count, Probe, COUNTSIZE,
+};
+
+/// Call sites probed by the calls monitor
+struct CallSites;
+
+/// Adds call count instrumentation logic to a module.
+///     1.  Counts are kept wherever the provided `Counters` put them
+///     2.  Every local function gets a counter incremented at the start
+///         of its body, counting how often it is called (or started).
+///     3.  With `call_sites` every `call` instr is instrumented by
+///         `instrument_func_sites` too, its counter is laid out after
+///         the entry counter of the calling function.
+///     4.  Record a `Probe` for every counter so the captured memory
+///         can be decoded afterwards.
+///
+/// Returns the probes and the size (in bytes) of their counters.
+pub fn instrument(
+    module: &mut Module,
+    counters: &Counters,
+    call_sites: bool,
+) -> walrus::Result<(Vec<Probe>, usize)> {
+    // Iterate on local functions
+    let mut curr_foffset = 0;
+    let mut probes: Vec<Probe> = Vec::new();
+    for (id, func) in module.funcs.iter_local_mut() {
+        let entry = func.entry_block();
+        probes.push(Probe {
+            count: Count::Counter(curr_foffset),
+            func: id,
+            seq_path: vec![entry],
+            index: 0,
+            opcode: "entry".to_string(),
+            outcome: id.index(),
+            path: vec![],
+            callee: Some(id),
+            backward: false,
+        });
+
+        let mut size = COUNTSIZE;
+        if call_sites {
+            size += instrument_func_sites(
+                id,
+                func,
+                curr_foffset + COUNTSIZE,
+                counters,
+                &CallSites,
+                &mut probes,
+            )?;
+        }
+
+        // Count the call itself last so call positions stay valid
+        let mut instr_builder = func.builder_mut().instr_seq(entry);
+        counters.insert_increment(&mut instr_builder, 0, curr_foffset);
+
+        curr_foffset += size;
+    }
+
+    Ok((probes, curr_foffset))
+}
+
+impl Sites for CallSites {
+    fn select(
+        &self,
+        _func: &LocalFunction,
+        _seq_path: &[InstrSeqId],
+        _index: usize,
+        instr: &Instr,
+    ) -> Option<usize> {
+        match instr {
+            Instr::Call(_) => Some(1),
+            _ => None,
+        }
+    }
+
+    fn emit(
+        &self,
+        instr_builder: &mut InstrSeqBuilder,
+        pos: usize,
+        _instr: &Instr,
+        offset: usize,
+        counters: &Counters,
+    ) -> walrus::Result<usize> {
+        Ok(counters.insert_increment(instr_builder, pos, offset))
+    }
+
+    fn describe(
+        &self,
+        _func: &LocalFunction,
+        _seq_path: &[InstrSeqId],
+        instr: &Instr,
+        probe: &mut Probe,
+    ) {
+        // Record the call site with the called function
+        if let Instr::Call(call) = instr {
+            probe.outcome = call.func.index();
+            probe.callee = Some(call.func);
+        }
+    }
+}
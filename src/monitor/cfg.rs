@@ -0,0 +1,435 @@
+use std::collections::{BTreeMap, HashMap};
+
+use walrus::{
+    ir::{BinaryOp, Instr, InstrSeqId, Value},
+    InstrSeqBuilder, LocalFunction, LocalId,
+};
+
+/// Index of the block a function starts in
+pub(crate) const ENTRY: usize = 0;
+/// Index of the virtual block every return leads to
+pub(crate) const EXIT: usize = 1;
+
+/// Control flow graph of a local function. Its nodes are straight-line
+/// basic blocks within the function's (nested) instruction sequences.
+/// A block ends after a branch, `return`, `unreachable` or a nested
+/// `block`, `loop` or `if`, which then starts a new (possibly empty)
+/// block after it. Calls do not end blocks.
+#[derive(Debug)]
+pub(crate) struct Cfg {
+    pub(crate) blocks: Vec<BasicBlock>,
+    /// The first edge is the virtual edge from `EXIT` to `ENTRY`
+    pub(crate) edges: Vec<Edge>,
+    /// Blocks of every instruction sequence ordered by start
+    seq_blocks: HashMap<InstrSeqId, Vec<usize>>,
+}
+
+#[derive(Debug)]
+pub(crate) struct BasicBlock {
+    /// Instruction sequences leading from the function body down to
+    /// the one holding the block
+    pub(crate) seq_path: Vec<InstrSeqId>,
+    /// Range of (original) instr positions in the sequence
+    pub(crate) start: usize,
+    pub(crate) end: usize,
+    /// Number of loops the block is nested in
+    pub(crate) loop_depth: usize,
+}
+
+impl BasicBlock {
+    pub(crate) fn seq(&self) -> InstrSeqId {
+        self.seq_path[self.seq_path.len() - 1]
+    }
+}
+
+#[derive(Debug)]
+pub(crate) struct Edge {
+    pub(crate) from: usize,
+    pub(crate) to: usize,
+    pub(crate) site: Site,
+    /// Whether the edge branches back to the start of a loop
+    pub(crate) back: bool,
+}
+
+/// Where a counter for an edge can be inserted. Positions refer to
+/// the original (uninstrumented) instruction sequence.
+#[derive(Debug, Clone, Copy)]
+pub(crate) enum Site {
+    /// Anywhere in a block whose only successor is the edge's target,
+    /// given as the position to insert at
+    At(InstrSeqId, usize),
+    /// Before the `br_if` at the position, when it branches
+    Taken(InstrSeqId, usize),
+    /// Before the `br_table` at the position, when it picks the
+    /// target of the given index out of all (the last being default)
+    Target(InstrSeqId, usize, usize, usize),
+    /// The edge from `EXIT` back to `ENTRY`, which cannot be counted
+    Virtual,
+}
+
+impl Cfg {
+    /// Builds the control flow graph of a local function.
+    pub(crate) fn build(func: &LocalFunction) -> Cfg {
+        let body = func.entry_block();
+        let mut builder = Builder {
+            func,
+            cfg: Cfg {
+                blocks: vec![],
+                edges: vec![],
+                seq_blocks: HashMap::new(),
+            },
+            // Branching to the function body returns from it
+            labels: vec![(body, EXIT, false)],
+        };
+
+        let entry = builder.add_block(vec![body], 0, 0);
+        builder.add_block(vec![body], 0, 0);
+        builder.add_edge(EXIT, ENTRY, Site::Virtual);
+        builder.build_seq(body, &[body], entry, EXIT, 0);
+
+        let mut cfg = builder.cfg;
+        for blocks in cfg.seq_blocks.values_mut() {
+            blocks.sort_by_key(|block| cfg.blocks[*block].start);
+        }
+        cfg
+    }
+
+    /// Returns the block holding the instr at `index` of `seq`.
+    pub(crate) fn block_at(&self, seq: InstrSeqId, index: usize) -> Option<usize> {
+        let blocks = self.seq_blocks.get(&seq)?;
+        blocks.iter().copied().find(|block| {
+            let block = &self.blocks[*block];
+            block.start <= index && index < block.end
+        })
+    }
+
+    /// Returns the indices of the edges leaving a block, in the order
+    /// of the outcomes of the instr ending it.
+    pub(crate) fn successors(&self, block: usize) -> Vec<usize> {
+        (0..self.edges.len())
+            .filter(|edge| self.edges[*edge].from == block)
+            .collect()
+    }
+
+    /// Returns the indices of the edges entering a block.
+    pub(crate) fn predecessors(&self, block: usize) -> Vec<usize> {
+        (0..self.edges.len())
+            .filter(|edge| self.edges[*edge].to == block)
+            .collect()
+    }
+
+    /// Picks the edges to count so all others can be derived by flow
+    /// conservation: the chords of a maximum spanning tree (Knuth).
+    /// Edges nested in more loops weigh more so they stay uncounted,
+    /// the virtual edge is always part of the tree.
+    pub(crate) fn chords(&self) -> Vec<bool> {
+        let weight = |edge: &Edge| match edge.site {
+            Site::Virtual => usize::MAX,
+            _ => self.blocks[edge.from].loop_depth,
+        };
+        let mut order: Vec<usize> = (0..self.edges.len()).collect();
+        order.sort_by_key(|edge| std::cmp::Reverse(weight(&self.edges[*edge])));
+
+        // Kruskal's algorithm on the undirected graph
+        let mut parents: Vec<usize> = (0..self.blocks.len()).collect();
+        let mut chords = vec![true; self.edges.len()];
+        for edge in order {
+            let from = find(&mut parents, self.edges[edge].from);
+            let to = find(&mut parents, self.edges[edge].to);
+            if from != to {
+                parents[from] = to;
+                chords[edge] = false;
+            }
+        }
+
+        chords
+    }
+
+    /// Inserts instrs on some edges of the function the graph was built
+    /// for. `insert` adds the instrs of an edge at a position of an instr
+    /// sequence. Branches get them in an `if` testing the branch operand,
+    /// which is copied using `local_id`. Instrs of edges sharing a
+    /// position are executed in the order of the edges.
+    pub(crate) fn insert_on_edges(
+        &self,
+        func: &mut LocalFunction,
+        edges: &[usize],
+        local_id: LocalId,
+        insert: impl Fn(&mut InstrSeqBuilder, usize, usize),
+    ) {
+        // Insert from the back of every sequence so the original
+        // positions of the remaining sites stay valid
+        let mut edges = edges.to_vec();
+        edges.sort_by_key(|edge| std::cmp::Reverse((self.edges[*edge].site.position(), *edge)));
+
+        for edge in edges {
+            let site = self.edges[edge].site;
+            let (seq, pos) = match site.position() {
+                Some(position) => position,
+                None => unreachable!("Nothing can be inserted on the virtual edge"),
+            };
+
+            let mut instr_builder = func.builder_mut().instr_seq(seq);
+            match site {
+                Site::At(..) => insert(&mut instr_builder, pos, edge),
+                Site::Taken(..) | Site::Target(..) => {
+                    // Copy the operand and test it for the edge's target
+                    instr_builder
+                        .local_tee_at(pos, local_id)
+                        .local_get_at(pos + 1, local_id);
+                    let mut i = pos + 2;
+                    if let Site::Target(_, _, target, ntargets) = site {
+                        // The default target is picked for any larger index
+                        let op = match target + 1 == ntargets {
+                            true => BinaryOp::I32GeU,
+                            false => BinaryOp::I32Eq,
+                        };
+                        instr_builder
+                            .const_at(i, Value::I32(target as i32))
+                            .binop_at(i + 1, op);
+                        i += 2;
+                    }
+
+                    instr_builder.if_else_at(i, None, |then| insert(then, 0, edge), |_| {});
+                }
+                Site::Virtual => {}
+            }
+        }
+    }
+
+    /// Derives the count of every edge from the counters of the chords
+    /// by flow conservation. Counts are given as the signed sum of
+    /// counter offsets, `counters` holds the offset of every chord.
+    pub(crate) fn derive(&self, counters: &[Option<usize>]) -> Vec<Vec<(usize, i64)>> {
+        let mut counts: Vec<Option<BTreeMap<usize, i64>>> = counters
+            .iter()
+            .map(|offset| offset.map(|offset| BTreeMap::from([(offset, 1)])))
+            .collect();
+
+        // Self loops leave the flow through a block unchanged
+        let mut incident: Vec<Vec<usize>> = vec![vec![]; self.blocks.len()];
+        for (i, edge) in self.edges.iter().enumerate() {
+            if edge.from != edge.to {
+                incident[edge.from].push(i);
+                incident[edge.to].push(i);
+            }
+        }
+
+        // Repeatedly solve blocks with a single edge of unknown count,
+        // which works its way from the leaves of the spanning tree in
+        let mut solved = true;
+        while solved {
+            solved = false;
+            for (block, edges) in incident.iter().enumerate() {
+                let unknown: Vec<usize> = edges
+                    .iter()
+                    .copied()
+                    .filter(|edge| counts[*edge].is_none())
+                    .collect();
+                let [unknown] = unknown[..] else {
+                    continue;
+                };
+
+                // Inflow equals outflow
+                let sign = if self.edges[unknown].to == block {
+                    1
+                } else {
+                    -1
+                };
+                let mut count = BTreeMap::new();
+                for edge in edges.iter().copied().filter(|edge| *edge != unknown) {
+                    let edge_sign = if self.edges[edge].to == block { -1 } else { 1 };
+                    for (offset, factor) in counts[edge].as_ref().unwrap() {
+                        *count.entry(*offset).or_insert(0) += sign * edge_sign * factor;
+                    }
+                }
+                count.retain(|_, factor| *factor != 0);
+                counts[unknown] = Some(count);
+                solved = true;
+            }
+        }
+
+        counts
+            .into_iter()
+            .map(|count| count.unwrap_or_default().into_iter().collect())
+            .collect()
+    }
+}
+
+impl Site {
+    /// Sequence and original position the site inserts at
+    fn position(&self) -> Option<(InstrSeqId, usize)> {
+        match *self {
+            Site::At(seq, pos) | Site::Taken(seq, pos) | Site::Target(seq, pos, ..) => {
+                Some((seq, pos))
+            }
+            Site::Virtual => None,
+        }
+    }
+}
+
+/// Finds the representative of a set, compressing the path to it.
+fn find(parents: &mut [usize], node: usize) -> usize {
+    let mut root = node;
+    while parents[root] != root {
+        root = parents[root];
+    }
+
+    let mut node = node;
+    while parents[node] != root {
+        let next = parents[node];
+        parents[node] = root;
+        node = next;
+    }
+    root
+}
+
+struct Builder<'a> {
+    func: &'a LocalFunction,
+    cfg: Cfg,
+    /// Enclosing instruction sequences, the block a branch to them
+    /// continues in and whether they are loops, innermost last
+    labels: Vec<(InstrSeqId, usize, bool)>,
+}
+
+impl Builder<'_> {
+    fn add_block(&mut self, seq_path: Vec<InstrSeqId>, start: usize, loop_depth: usize) -> usize {
+        let block = self.cfg.blocks.len();
+        self.cfg.blocks.push(BasicBlock {
+            seq_path,
+            start,
+            end: start,
+            loop_depth,
+        });
+        self.cfg
+            .seq_blocks
+            .entry(self.cfg.blocks[block].seq())
+            .or_default()
+            .push(block);
+        block
+    }
+
+    fn add_edge(&mut self, from: usize, to: usize, site: Site) {
+        self.cfg.edges.push(Edge {
+            from,
+            to,
+            site,
+            back: false,
+        });
+    }
+
+    /// Adds the edge of a branch to the sequence `label`.
+    fn add_branch(&mut self, from: usize, label: InstrSeqId, site: Site) {
+        let (to, back) = match self.labels.iter().rev().find(|(seq, ..)| *seq == label) {
+            Some((_, block, is_loop)) => (*block, *is_loop),
+            None => unreachable!("Branch to a sequence that does not enclose it"),
+        };
+        self.cfg.edges.push(Edge {
+            from,
+            to,
+            site,
+            back,
+        });
+    }
+
+    /// Adds the blocks of an instruction sequence, starting with the
+    /// already added `first`. Falling off its end continues in `cont`.
+    fn build_seq(
+        &mut self,
+        seq: InstrSeqId,
+        seq_path: &[InstrSeqId],
+        first: usize,
+        cont: usize,
+        loop_depth: usize,
+    ) {
+        let instrs = &self.func.block(seq).instrs;
+        let mut block = first;
+        let mut falls_through = true;
+        for (i, (instr, _)) in instrs.iter().enumerate() {
+            self.cfg.blocks[block].end = i + 1;
+
+            // Whether the instr ends the block without falling through
+            let mut ends = false;
+
+            match instr {
+                Instr::Block(nested) => {
+                    let after = self.add_block(seq_path.to_vec(), i + 1, loop_depth);
+                    let path = [seq_path, &[nested.seq]].concat();
+                    let body = self.add_block(path.clone(), 0, loop_depth);
+                    self.add_edge(block, body, Site::At(seq, i));
+
+                    self.labels.push((nested.seq, after, false));
+                    self.build_seq(nested.seq, &path, body, after, loop_depth);
+                    self.labels.pop();
+                    block = after;
+                }
+                Instr::Loop(nested) => {
+                    let after = self.add_block(seq_path.to_vec(), i + 1, loop_depth);
+                    let path = [seq_path, &[nested.seq]].concat();
+                    let body = self.add_block(path.clone(), 0, loop_depth + 1);
+                    self.add_edge(block, body, Site::At(seq, i));
+
+                    // Branching to a loop starts its next iteration
+                    self.labels.push((nested.seq, body, true));
+                    self.build_seq(nested.seq, &path, body, after, loop_depth + 1);
+                    self.labels.pop();
+                    block = after;
+                }
+                Instr::IfElse(nested) => {
+                    let after = self.add_block(seq_path.to_vec(), i + 1, loop_depth);
+                    for arm in [nested.consequent, nested.alternative] {
+                        let path = [seq_path, &[arm]].concat();
+                        let body = self.add_block(path.clone(), 0, loop_depth);
+                        self.add_edge(block, body, Site::At(arm, 0));
+
+                        self.labels.push((arm, after, false));
+                        self.build_seq(arm, &path, body, after, loop_depth);
+                        self.labels.pop();
+                    }
+                    block = after;
+                }
+                Instr::Br(br) => {
+                    self.add_branch(block, br.block, Site::At(seq, i));
+                    ends = true;
+                }
+                Instr::BrIf(br_if) => {
+                    self.add_branch(block, br_if.block, Site::Taken(seq, i));
+
+                    // Falling off the sequence's end is added below
+                    if i + 1 < instrs.len() {
+                        let next = self.add_block(seq_path.to_vec(), i + 1, loop_depth);
+                        self.add_edge(block, next, Site::At(seq, i + 1));
+                        block = next;
+                    }
+                }
+                Instr::BrTable(table) => {
+                    let ntargets = table.blocks.len() + 1;
+                    for (k, target) in table.blocks.iter().chain([&table.default]).enumerate() {
+                        self.add_branch(block, *target, Site::Target(seq, i, k, ntargets));
+                    }
+                    ends = true;
+                }
+                Instr::Return(_) | Instr::Unreachable(_) => {
+                    // Traps are treated like returns to keep the flow
+                    self.add_edge(block, EXIT, Site::At(seq, i));
+                    ends = true;
+                }
+                _ => {}
+            }
+
+            // Dead code after an unconditional branch gets a block of
+            // its own without predecessors
+            if ends {
+                match i + 1 < instrs.len() {
+                    true => block = self.add_block(seq_path.to_vec(), i + 1, loop_depth),
+                    false => falls_through = false,
+                }
+            }
+        }
+
+        if falls_through {
+            self.cfg.blocks[block].end = instrs.len();
+            self.add_edge(block, cont, Site::At(seq, instrs.len()));
+        }
+    }
+}
@@ -0,0 +1,98 @@
+use walrus::{
+    ir::{Instr, InstrLocId, InstrSeqId},
+    FunctionId, LocalFunction, Module,
+};
+
+/// Probe code detached from the instruction sequences of a module so
+/// that the next monitor only sees the original instructions.
+pub(crate) struct Hidden {
+    runs: Vec<Run>,
+}
+
+/// Consecutive probe instrs of an instruction sequence
+struct Run {
+    func: FunctionId,
+    seq: InstrSeqId,
+    /// Original instr the run preceded, `None` for the end of the
+    /// sequence
+    anchor: Option<InstrLocId>,
+    instrs: Vec<(Instr, InstrLocId)>,
+}
+
+/// Detaches all instrs added by earlier monitors. Instrs parsed from
+/// the module carry the offset they were read from, instrs added by
+/// probes the default location.
+pub(crate) fn hide(module: &mut Module) -> Hidden {
+    let mut hidden = Hidden { runs: vec![] };
+    for (id, func) in module.funcs.iter_local_mut() {
+        let entry = func.entry_block();
+        hide_seq(id, func, entry, &mut hidden);
+    }
+
+    hidden
+}
+
+fn hide_seq(func_id: FunctionId, func: &mut LocalFunction, seq: InstrSeqId, hidden: &mut Hidden) {
+    let mut run = vec![];
+    let mut kept = vec![];
+    for (instr, loc) in std::mem::take(&mut func.block_mut(seq).instrs) {
+        if loc.is_default() {
+            run.push((instr, loc));
+            continue;
+        }
+        if !run.is_empty() {
+            hidden.runs.push(Run {
+                func: func_id,
+                seq,
+                anchor: Some(loc),
+                instrs: std::mem::take(&mut run),
+            });
+        }
+        kept.push((instr, loc));
+    }
+    if !run.is_empty() {
+        hidden.runs.push(Run {
+            func: func_id,
+            seq,
+            anchor: None,
+            instrs: run,
+        });
+    }
+
+    // Recurse for nested blocks, the ones added by probes went
+    // away with them
+    let nested: Vec<InstrSeqId> = kept
+        .iter()
+        .flat_map(|(instr, _)| match instr {
+            Instr::Block(block) => vec![block.seq],
+            Instr::Loop(block) => vec![block.seq],
+            Instr::IfElse(block) => vec![block.consequent, block.alternative],
+            _ => vec![],
+        })
+        .collect();
+    func.block_mut(seq).instrs = kept;
+    for seq in nested {
+        hide_seq(func_id, func, seq, hidden);
+    }
+}
+
+/// Puts the detached instrs back in front of the original instr they
+/// preceded, ahead of any probe code added there since. Probes leave
+/// the stack as they found it, so the code of every monitor still sees
+/// the operands of the instr it was added for.
+pub(crate) fn restore(module: &mut Module, hidden: Hidden) {
+    for run in hidden.runs {
+        let func = module.funcs.get_mut(run.func).kind.unwrap_local_mut();
+        let instrs = &mut func.block_mut(run.seq).instrs;
+
+        let mut pos = match run.anchor {
+            Some(anchor) => instrs.iter().position(|(_, loc)| *loc == anchor).unwrap(),
+            None => instrs.len(),
+        };
+        while pos > 0 && instrs[pos - 1].1.is_default() {
+            pos -= 1;
+        }
+
+        instrs.splice(pos..pos, run.instrs);
+    }
+}
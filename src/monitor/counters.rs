@@ -0,0 +1,1224 @@
+use anyhow::bail;
+use walrus::{
+    ir::{
+        BinaryOp, Binop, Block, BrTable, Call, Const, GlobalGet, GlobalSet, Instr, InstrLocId,
+        InstrSeq, Load, LoadKind, LocalGet, LocalSet, MemArg, Return, Select, Store, StoreKind,
+        UnaryOp, Value, VisitorMut,
+    },
+    ExportItem, FunctionBuilder, FunctionId, FunctionKind, GlobalId, GlobalKind, InitExpr,
+    InstrSeqBuilder, LocalId, Memory, MemoryId, Module, ValType,
+};
+
+use super::{
+    Backend, Layout, Options, Sampling, Switch, BASEGLOBAL, COUNTPREFIX, COUNTSIZE, ENABLEDGLOBAL,
+    ENABLEFUNC, HOSTMODULE, MAXPAGES, MEMREGION, PAGESIZE, RESETFUNC,
+};
+
+/// Where probes record their counts, set up once per module
+/// according to the chosen `Backend`. Offsets passed to the `insert_*`
+/// methods are relative to the counter region of the monitor being
+/// instrumented.
+#[derive(Clone, Copy)]
+pub struct Counters {
+    storage: Storage,
+    /// Byte offset of the counter region
+    region: usize,
+    /// Whether increments are only recorded for some executions
+    sampling: Option<Sampled>,
+    /// Global switching recording on and off at runtime, if any
+    enabled: Option<GlobalId>,
+}
+
+/// Sampling of the executions recorded by increments. Every sampled
+/// counter has a countdown to its next recorded execution, at the same
+/// offset from the address held by `countdowns` as the counter from the
+/// start of the counts.
+#[derive(Clone, Copy)]
+struct Sampled {
+    sampling: Sampling,
+    countdowns: GlobalId,
+    /// Local for the countdown of a probe
+    local_id: LocalId,
+    /// Local for the address of an indexed countdown
+    address_id: LocalId,
+}
+
+/// Storage of the counts of all monitors
+#[derive(Clone, Copy)]
+enum Storage {
+    /// Counts live in the dedicated instrument memory
+    Memory { mem_id: MemoryId, local_id: LocalId },
+    /// Counts live in a region appended to the module's own memory,
+    /// starting at the address held by the `base` global
+    SingleMemory {
+        mem_id: MemoryId,
+        base: GlobalId,
+        local_id: LocalId,
+    },
+    /// Counts are reported to imported host functions
+    HostCall {
+        probe: FunctionId,
+        branch: FunctionId,
+        /// Import clearing the counts, with a runtime switch only
+        reset: Option<FunctionId>,
+        local_id: LocalId,
+    },
+    /// Counts live in one global per counter. Probes call the
+    /// placeholder imports `probe` and `branch` like the host call
+    /// backend until `finish` replaces them with global increments.
+    Globals {
+        probe: FunctionId,
+        branch: FunctionId,
+        local_id: LocalId,
+    },
+}
+
+impl Counters {
+    /// Adds the memory, globals or imports required by the backend
+    /// to the module. Fails if sampling is asked for and the backend
+    /// keeps no counts in memory.
+    pub(crate) fn add(module: &mut Module, options: &Options) -> walrus::Result<Counters> {
+        let backend = options.backend;
+        if options.sampling.is_some() && matches!(backend, Backend::HostCall | Backend::Globals) {
+            bail!("Only the memory and single backends can sample probes");
+        }
+
+        // Create local var to hold intermediate values of probes
+        let local_id = module.locals.add(ValType::I32);
+
+        let storage = match backend {
+            Backend::Memory => {
+                // Add linear memory for storing counts
+                // XXX: Might need to initialize to 0
+                let mem_id = module.memories.add_local(false, 1, None);
+                module.exports.add(MEMREGION, ExportItem::Memory(mem_id));
+
+                Storage::Memory { mem_id, local_id }
+            }
+            Backend::SingleMemory => {
+                let memories: Vec<_> = module.memories.iter().collect();
+                let mem_id = match memories[..] {
+                    [memory] if memory.import.is_some() => {
+                        bail!("Single memory backend does not support imported memories")
+                    }
+                    [memory] => memory.id(),
+                    [] => module.memories.add_local(false, 0, None),
+                    _ => bail!("Single memory backend requires a module with one memory"),
+                };
+
+                // Counts start right after the initial pages holding
+                // the data segments
+                let initial = module.memories.get(mem_id).initial;
+                let base = module.globals.add_local(
+                    ValType::I32,
+                    true,
+                    InitExpr::Value(Value::I32((initial as usize * PAGESIZE) as i32)),
+                );
+                module.exports.add(MEMREGION, ExportItem::Memory(mem_id));
+                module.exports.add(BASEGLOBAL, ExportItem::Global(base));
+
+                Storage::SingleMemory {
+                    mem_id,
+                    base,
+                    local_id,
+                }
+            }
+            Backend::HostCall | Backend::Globals => {
+                let probe_ty = module.types.add(&[ValType::I32], &[]);
+                let branch_ty = module.types.add(&[ValType::I32, ValType::I32], &[]);
+                let (probe, _) = module.add_import_func(HOSTMODULE, "probe", probe_ty);
+                let (branch, _) = module.add_import_func(HOSTMODULE, "branch", branch_ty);
+
+                // The host clears its counts on reset
+                let reset = match (backend, options.switch) {
+                    (Backend::HostCall, Switch::Enabled | Switch::Disabled) => {
+                        let reset_ty = module.types.add(&[], &[]);
+                        Some(module.add_import_func(HOSTMODULE, "reset", reset_ty).0)
+                    }
+                    _ => None,
+                };
+
+                match backend {
+                    Backend::Globals => Storage::Globals {
+                        probe,
+                        branch,
+                        local_id,
+                    },
+                    _ => Storage::HostCall {
+                        probe,
+                        branch,
+                        reset,
+                        local_id,
+                    },
+                }
+            }
+        };
+
+        // Probes record while the global is non-zero
+        let enabled = match options.switch {
+            Switch::Absent => None,
+            Switch::Enabled | Switch::Disabled => {
+                let initial = matches!(options.switch, Switch::Enabled) as i32;
+                let global = module.globals.add_local(
+                    ValType::I32,
+                    true,
+                    InitExpr::Value(Value::I32(initial)),
+                );
+                module
+                    .exports
+                    .add(ENABLEDGLOBAL, ExportItem::Global(global));
+                Some(global)
+            }
+        };
+
+        // The countdowns follow the counts, their address is set once
+        // the size of the counts is known
+        let sampling = options.sampling.map(|sampling| Sampled {
+            sampling,
+            countdowns: module.globals.add_local(
+                ValType::I32,
+                matches!(backend, Backend::SingleMemory),
+                InitExpr::Value(Value::I32(0)),
+            ),
+            local_id: module.locals.add(ValType::I32),
+            address_id: module.locals.add(ValType::I32),
+        });
+
+        Ok(Counters {
+            storage,
+            region: 0,
+            sampling,
+            enabled,
+        })
+    }
+
+    /// Returns counters placing offsets in the region starting at byte
+    /// offset `region` of the counts.
+    pub(crate) fn region(&self, region: usize) -> Counters {
+        Counters {
+            storage: self.storage,
+            region,
+            sampling: self.sampling,
+            enabled: self.enabled,
+        }
+    }
+
+    /// Returns the id of the counter at `offset`, as reported to the
+    /// host and listed in the probe map.
+    pub fn id(&self, offset: usize) -> usize {
+        (self.region + offset) / COUNTSIZE
+    }
+
+    /// Returns the memory added to the module to hold the counts, if
+    /// any, which monitors leave alone.
+    pub fn memory(&self) -> Option<MemoryId> {
+        match &self.storage {
+            Storage::Memory { mem_id, .. } => Some(*mem_id),
+            _ => None,
+        }
+    }
+
+    /// Sizes the memory region according to the layout (or adds the
+    /// globals holding the counts) and adds the functions of the
+    /// runtime switch. Fails if there is no room left for the counter
+    /// pages in the memory.
+    pub(crate) fn finish(&self, module: &mut Module, layout: &Layout) -> walrus::Result<()> {
+        let reset = match &self.storage {
+            Storage::Memory { mem_id, .. } => {
+                // Update size of memory region
+                let mem_region: &mut Memory = module.memories.get_mut(*mem_id);
+                mem_region.initial = layout.pages;
+                mem_region.maximum = Some(layout.pages);
+                self.place_countdowns(module, layout.size);
+                Reset::Memory {
+                    mem_id: *mem_id,
+                    base: None,
+                    size: layout.size,
+                }
+            }
+            Storage::SingleMemory { mem_id, base, .. } => {
+                let memory = module.memories.get_mut(*mem_id);
+                let limit = memory.maximum.unwrap_or(memory.initial);
+                if limit + layout.pages > MAXPAGES {
+                    bail!(
+                        "Counts require {} bytes ({} pages) but the memory has room for only {} more pages",
+                        layout.size,
+                        layout.pages,
+                        MAXPAGES - limit
+                    );
+                }
+                let initial = memory.initial;
+                memory.initial += layout.pages;
+                memory.maximum = memory.maximum.map(|maximum| maximum + layout.pages);
+                self.place_countdowns(module, initial as usize * PAGESIZE + layout.size);
+
+                // The countdowns move along with the counts
+                let mut moved = vec![*base];
+                moved.extend(self.sampling.map(|sampled| sampled.countdowns));
+                hide_counter_pages(module, *mem_id, &moved, layout.pages);
+                Reset::Memory {
+                    mem_id: *mem_id,
+                    base: Some(*base),
+                    size: layout.size,
+                }
+            }
+            Storage::HostCall { reset, .. } => Reset::Host(*reset),
+            Storage::Globals { probe, branch, .. } => Reset::Globals(add_global_counts(
+                module,
+                *probe,
+                *branch,
+                layout.size / COUNTSIZE,
+            )),
+        };
+
+        if let Some(enabled) = self.enabled {
+            add_switch_funcs(module, enabled, reset);
+        }
+
+        Ok(())
+    }
+
+    /// Sets the address of the countdowns of sampled counters.
+    fn place_countdowns(&self, module: &mut Module, address: usize) {
+        if let Some(sampled) = self.sampling {
+            module.globals.get_mut(sampled.countdowns).kind =
+                GlobalKind::Local(InitExpr::Value(Value::I32(address as i32)));
+        }
+    }
+
+    /// Inserts instrs incrementing the count stored at `offset` at
+    /// position `pos` of an instruction sequence. Returns the number
+    /// of inserted instrs.
+    pub fn insert_increment(
+        &self,
+        instr_builder: &mut InstrSeqBuilder,
+        pos: usize,
+        offset: usize,
+    ) -> usize {
+        self.insert_guarded(
+            instr_builder,
+            pos,
+            Some((offset, None)),
+            |instr_builder, pos| self.increment(instr_builder, pos, offset),
+        )
+    }
+
+    /// Inserts instrs popping an i32 index off the stack and
+    /// incrementing the count stored at `offset + index * COUNTSIZE`
+    /// at position `pos` of an instruction sequence. Returns the
+    /// number of inserted instrs.
+    pub fn insert_indexed_increment(
+        &self,
+        instr_builder: &mut InstrSeqBuilder,
+        pos: usize,
+        offset: usize,
+    ) -> usize {
+        if self.sampling.is_none() && self.enabled.is_none() {
+            return self.indexed_increment(instr_builder, pos, offset);
+        }
+
+        // Keep the index aside while the guards are tested
+        let local_id = self.local_id();
+        instr_builder.local_set_at(pos, local_id);
+        1 + self.insert_guarded(
+            instr_builder,
+            pos + 1,
+            Some((offset, Some(local_id))),
+            |instr_builder, pos| {
+                instr_builder.local_get_at(pos, local_id);
+                1 + self.indexed_increment(instr_builder, pos + 1, offset)
+            },
+        )
+    }
+
+    /// Inserts the instrs added by `insert` at position `pos` of an
+    /// instruction sequence, executing them only while recording is
+    /// switched on at runtime. Probes recording anything besides the
+    /// `insert_*` methods (e.g. by calling the host) wrap it in this.
+    /// The instrs must leave the stack as they found it. Returns the
+    /// number of inserted instrs.
+    pub fn insert_recording(
+        &self,
+        instr_builder: &mut InstrSeqBuilder,
+        pos: usize,
+        insert: impl FnOnce(&mut InstrSeqBuilder, usize) -> usize,
+    ) -> usize {
+        self.insert_guarded(instr_builder, pos, None, insert)
+    }
+
+    /// Like `insert_recording`, also sampling executions by the
+    /// countdown of the counter at `sampled` if given, see
+    /// `insert_sampled`.
+    fn insert_guarded(
+        &self,
+        instr_builder: &mut InstrSeqBuilder,
+        pos: usize,
+        sampled: Option<(usize, Option<LocalId>)>,
+        insert: impl FnOnce(&mut InstrSeqBuilder, usize) -> usize,
+    ) -> usize {
+        let guarded = |instr_builder: &mut InstrSeqBuilder, pos: usize| match sampled {
+            Some((offset, index)) => self.insert_sampled(instr_builder, pos, offset, index, insert),
+            None => insert(instr_builder, pos),
+        };
+
+        match self.enabled {
+            Some(enabled) => {
+                instr_builder.global_get_at(pos, enabled).if_else_at(
+                    pos + 1,
+                    None,
+                    |then| {
+                        guarded(then, 0);
+                    },
+                    |_| {},
+                );
+                2
+            }
+            None => guarded(instr_builder, pos),
+        }
+    }
+
+    /// Returns the local for intermediate values of probes.
+    fn local_id(&self) -> LocalId {
+        match &self.storage {
+            Storage::Memory { local_id, .. }
+            | Storage::SingleMemory { local_id, .. }
+            | Storage::HostCall { local_id, .. }
+            | Storage::Globals { local_id, .. } => *local_id,
+        }
+    }
+
+    /// Inserts the instrs added by `insert` at position `pos` of an
+    /// instruction sequence, in a block executing them only for sampled
+    /// executions when sampling. Every execution decrements the
+    /// countdown of the counter at `offset` (plus the index held by the
+    /// `index` local times `COUNTSIZE`), the ones taking it below the
+    /// burst length are sampled and the last one of them reloads the
+    /// period. Countdowns start at 0, the first execution of a counter
+    /// starts it at a phase staggered by its id instead so its count
+    /// stays unbiased. Returns the number of inserted instrs.
+    fn insert_sampled(
+        &self,
+        instr_builder: &mut InstrSeqBuilder,
+        pos: usize,
+        offset: usize,
+        index: Option<LocalId>,
+        insert: impl FnOnce(&mut InstrSeqBuilder, usize) -> usize,
+    ) -> usize {
+        let Sampled {
+            sampling,
+            countdowns,
+            local_id,
+            address_id,
+        } = match self.sampling {
+            Some(sampled) => sampled,
+            None => return insert(instr_builder, pos),
+        };
+        let mem_id = match &self.storage {
+            Storage::Memory { mem_id, .. } | Storage::SingleMemory { mem_id, .. } => *mem_id,
+            _ => unreachable!("Only memory backends sample"),
+        };
+        let load = LoadKind::I32 { atomic: false };
+        let store = StoreKind::I32 { atomic: false };
+        let arg = MemArg {
+            align: COUNTSIZE as u32,
+            offset: (self.region + offset) as u32,
+        };
+        let id = (self.region + offset) / COUNTSIZE;
+
+        // Pushes the address of the countdown, minus its constant offset
+        let address = |seq: &mut InstrSeqBuilder| match index {
+            Some(_) => {
+                seq.local_get(address_id);
+            }
+            None => {
+                seq.global_get(countdowns);
+            }
+        };
+
+        instr_builder.block_at(pos, None, |sampled| {
+            let skip = sampled.id();
+
+            // The countdown of an indexed counter is indexed alike
+            if let Some(index) = index {
+                sampled
+                    .local_get(index)
+                    .i32_const(COUNTSIZE as i32)
+                    .binop(BinaryOp::I32Mul)
+                    .global_get(countdowns)
+                    .binop(BinaryOp::I32Add)
+                    .local_set(address_id);
+            }
+
+            // Count down, skipping executions before the burst
+            address(sampled);
+            address(sampled);
+            sampled
+                .load(mem_id, load, arg)
+                .i32_const(1)
+                .binop(BinaryOp::I32Sub)
+                .local_tee(local_id)
+                .store(mem_id, store, arg)
+                .local_get(local_id)
+                .i32_const(sampling.burst as i32)
+                .binop(BinaryOp::I32GeS)
+                .br_if(skip);
+
+            // Start a fresh countdown at the phase of its counter
+            sampled
+                .local_get(local_id)
+                .i32_const(0)
+                .binop(BinaryOp::I32LtS)
+                .if_else(
+                    None,
+                    |fresh| {
+                        match index {
+                            Some(index) => {
+                                fresh
+                                    .i32_const(id as i32)
+                                    .local_get(index)
+                                    .binop(BinaryOp::I32Add)
+                                    .i32_const(sampling.period as i32)
+                                    .binop(BinaryOp::I32RemU);
+                            }
+                            None => {
+                                fresh.i32_const((id % sampling.period as usize) as i32);
+                            }
+                        }
+                        fresh.local_set(local_id);
+                        address(fresh);
+                        fresh
+                            .local_get(local_id)
+                            .store(mem_id, store, arg)
+                            .local_get(local_id)
+                            .i32_const(sampling.burst as i32)
+                            .binop(BinaryOp::I32GeS)
+                            .br_if(skip);
+                    },
+                    |_| {},
+                );
+
+            // Reload at the end of the burst
+            sampled
+                .local_get(local_id)
+                .i32_const(1)
+                .binop(BinaryOp::I32LtS)
+                .if_else(
+                    None,
+                    |reload| {
+                        address(reload);
+                        reload
+                            .i32_const(sampling.period as i32)
+                            .store(mem_id, store, arg);
+                    },
+                    |_| {},
+                );
+
+            let end = sampled.instrs().len();
+            insert(sampled, end);
+        });
+
+        1
+    }
+
+    /// Inserts instrs incrementing the count stored at `offset`, see
+    /// `insert_increment`.
+    fn increment(&self, instr_builder: &mut InstrSeqBuilder, pos: usize, offset: usize) -> usize {
+        let offset = self.region + offset;
+        let (mem_id, offset) = match &self.storage {
+            Storage::Memory { mem_id, .. } => {
+                // Insert store and load index const instrs
+                instr_builder
+                    .const_at(pos, Value::I32(offset as i32))
+                    .const_at(pos + 1, Value::I32(offset as i32));
+                (*mem_id, 0)
+            }
+            Storage::SingleMemory { mem_id, base, .. } => {
+                // Insert store and load base instrs, the offset is
+                // added by the load and store instrs
+                instr_builder
+                    .global_get_at(pos, *base)
+                    .global_get_at(pos + 1, *base);
+                (*mem_id, offset as u32)
+            }
+            Storage::HostCall { probe, .. } | Storage::Globals { probe, .. } => {
+                // Report the probe id to the host
+                instr_builder
+                    .const_at(pos, Value::I32((offset / COUNTSIZE) as i32))
+                    .call_at(pos + 1, *probe);
+                return 2;
+            }
+        };
+
+        // Insert load, add and store instrs
+        2 + insert_add_one(instr_builder, pos + 2, mem_id, offset)
+    }
+
+    /// Inserts instrs popping an index and incrementing the count it
+    /// selects, see `insert_indexed_increment`.
+    fn indexed_increment(
+        &self,
+        instr_builder: &mut InstrSeqBuilder,
+        pos: usize,
+        offset: usize,
+    ) -> usize {
+        let offset = self.region + offset;
+        let mut i = pos;
+        let (mem_id, local_id) = match &self.storage {
+            Storage::Memory { mem_id, local_id } => {
+                // Scale the index to a memory offset
+                instr_builder
+                    .const_at(i, Value::I32(COUNTSIZE as i32))
+                    .binop_at(i + 1, BinaryOp::I32Mul);
+                i += 2;
+
+                (*mem_id, *local_id)
+            }
+            Storage::SingleMemory {
+                mem_id,
+                base,
+                local_id,
+            } => {
+                // Scale the index to a memory offset relative to the base
+                instr_builder
+                    .const_at(i, Value::I32(COUNTSIZE as i32))
+                    .binop_at(i + 1, BinaryOp::I32Mul)
+                    .global_get_at(i + 2, *base)
+                    .binop_at(i + 3, BinaryOp::I32Add);
+                i += 4;
+
+                (*mem_id, *local_id)
+            }
+            Storage::HostCall {
+                branch, local_id, ..
+            }
+            | Storage::Globals {
+                branch, local_id, ..
+            } => {
+                // Report the probe id of the first count and the index
+                instr_builder
+                    .local_set_at(i, *local_id)
+                    .const_at(i + 1, Value::I32((offset / COUNTSIZE) as i32))
+                    .local_get_at(i + 2, *local_id)
+                    .call_at(i + 3, *branch);
+
+                return 4;
+            }
+        };
+
+        // Need the address twice: one for load and one for store
+        instr_builder
+            .local_tee_at(i, local_id)
+            .local_get_at(i + 1, local_id);
+        i += 2;
+
+        i += insert_add_one(instr_builder, i, mem_id, offset as u32);
+
+        i - pos
+    }
+
+    /// Inserts instrs popping an i32 value off the stack and storing
+    /// it at `offset` if it is greater (unsigned) than the value stored
+    /// there at position `pos` of an instruction sequence. Returns the
+    /// number of inserted instrs. Fails for backends that only count.
+    pub fn insert_max(
+        &self,
+        instr_builder: &mut InstrSeqBuilder,
+        pos: usize,
+        offset: usize,
+    ) -> walrus::Result<usize> {
+        let (mem_id, local_id, address, offset) = match self.slot(offset) {
+            Some(slot) => slot,
+            None => bail!("Only the memory and single backends can keep maximum values"),
+        };
+        let load = Instr::Load(Load {
+            memory: mem_id,
+            kind: LoadKind::I32 { atomic: false },
+            arg: MemArg {
+                align: COUNTSIZE as u32,
+                offset,
+            },
+        });
+
+        // Store the value if it exceeds the current one, otherwise
+        // store the current one again
+        let instrs = [
+            Instr::LocalSet(LocalSet { local: local_id }),
+            address.clone(),
+            Instr::LocalGet(LocalGet { local: local_id }),
+            address.clone(),
+            load.clone(),
+            Instr::LocalGet(LocalGet { local: local_id }),
+            address,
+            load,
+            Instr::Binop(Binop {
+                op: BinaryOp::I32GtU,
+            }),
+            Instr::Select(Select { ty: None }),
+            Instr::Store(Store {
+                memory: mem_id,
+                kind: StoreKind::I32 { atomic: false },
+                arg: MemArg {
+                    align: COUNTSIZE as u32,
+                    offset,
+                },
+            }),
+        ];
+        Ok(self.insert_popping(instr_builder, pos, instrs))
+    }
+
+    /// Inserts instrs pushing the i32 value stored at `offset` at
+    /// position `pos` of an instruction sequence. Returns the number
+    /// of inserted instrs. Fails for backends that only count.
+    pub fn insert_load(
+        &self,
+        instr_builder: &mut InstrSeqBuilder,
+        pos: usize,
+        offset: usize,
+    ) -> walrus::Result<usize> {
+        let (mem_id, _, address, offset) = match self.slot(offset) {
+            Some(slot) => slot,
+            None => bail!("Only the memory and single backends can keep state"),
+        };
+
+        instr_builder.instr_at(pos, address).instr_at(
+            pos + 1,
+            Instr::Load(Load {
+                memory: mem_id,
+                kind: LoadKind::I32 { atomic: false },
+                arg: MemArg {
+                    align: COUNTSIZE as u32,
+                    offset,
+                },
+            }),
+        );
+
+        Ok(2)
+    }
+
+    /// Inserts instrs popping an i32 value off the stack and storing
+    /// it at `offset` at position `pos` of an instruction sequence.
+    /// Returns the number of inserted instrs. Fails for backends that
+    /// only count.
+    pub fn insert_store(
+        &self,
+        instr_builder: &mut InstrSeqBuilder,
+        pos: usize,
+        offset: usize,
+    ) -> walrus::Result<usize> {
+        let (mem_id, local_id, address, offset) = match self.slot(offset) {
+            Some(slot) => slot,
+            None => bail!("Only the memory and single backends can keep state"),
+        };
+
+        // The address goes below the value
+        let instrs = [
+            Instr::LocalSet(LocalSet { local: local_id }),
+            address,
+            Instr::LocalGet(LocalGet { local: local_id }),
+            Instr::Store(Store {
+                memory: mem_id,
+                kind: StoreKind::I32 { atomic: false },
+                arg: MemArg {
+                    align: COUNTSIZE as u32,
+                    offset,
+                },
+            }),
+        ];
+
+        Ok(self.insert_popping(instr_builder, pos, instrs))
+    }
+
+    /// Inserts `instrs`, which pop a value into a local with the first
+    /// one, at position `pos` of an instruction sequence. The others
+    /// only record while switched on. Returns the number of inserted
+    /// instrs.
+    fn insert_popping<const N: usize>(
+        &self,
+        instr_builder: &mut InstrSeqBuilder,
+        pos: usize,
+        instrs: [Instr; N],
+    ) -> usize {
+        let mut instrs = instrs.into_iter();
+        if let Some(first) = instrs.next() {
+            instr_builder.instr_at(pos, first);
+        }
+
+        1 + self.insert_recording(instr_builder, pos + 1, |instr_builder, pos| {
+            let count = instrs.len();
+            for (i, instr) in instrs.enumerate() {
+                instr_builder.instr_at(pos + i, instr);
+            }
+            count
+        })
+    }
+
+    /// Returns the memory, the local for intermediate values, the instr
+    /// pushing the address and the constant offset of the load or store
+    /// accessing the count at `offset`. `None` for backends that keep
+    /// no counts in memory.
+    fn slot(&self, offset: usize) -> Option<(MemoryId, LocalId, Instr, u32)> {
+        let offset = self.region + offset;
+        match &self.storage {
+            Storage::Memory { mem_id, local_id } => Some((
+                *mem_id,
+                *local_id,
+                Instr::Const(Const {
+                    value: Value::I32(offset as i32),
+                }),
+                0,
+            )),
+            Storage::SingleMemory {
+                mem_id,
+                base,
+                local_id,
+            } => Some((
+                *mem_id,
+                *local_id,
+                Instr::GlobalGet(GlobalGet { global: *base }),
+                offset as u32,
+            )),
+            Storage::HostCall { .. } | Storage::Globals { .. } => None,
+        }
+    }
+}
+
+/// Inserts instrs loading the count at the address on top of the
+/// stack (plus `offset`), adding one and storing it back to the
+/// address below. Returns the number of inserted instrs.
+fn insert_add_one(
+    instr_builder: &mut InstrSeqBuilder,
+    pos: usize,
+    mem_id: MemoryId,
+    offset: u32,
+) -> usize {
+    let mut i = pos;
+
+    // Insert load instr
+    instr_builder.instr_at(
+        i,
+        Instr::Load(Load {
+            memory: mem_id,
+            kind: LoadKind::I32 { atomic: false },
+            arg: MemArg {
+                align: COUNTSIZE as u32,
+                offset,
+            },
+        }),
+    );
+    i += 1;
+
+    // Insert increment count const
+    let incr_count = Const {
+        value: Value::I32(1),
+    };
+    let i32_const_incr_count: Instr = Instr::Const(incr_count);
+    instr_builder.instr_at(i, i32_const_incr_count);
+    i += 1;
+
+    // Insert add instr
+    instr_builder.instr_at(
+        i,
+        Instr::Binop(Binop {
+            op: BinaryOp::I32Add,
+        }),
+    );
+    i += 1;
+
+    // Insert store instr
+    instr_builder.instr_at(
+        i,
+        Instr::Store(Store {
+            memory: mem_id,
+            kind: StoreKind::I32 { atomic: false },
+            arg: MemArg {
+                align: COUNTSIZE as u32,
+                offset,
+            },
+        }),
+    );
+    i += 1;
+
+    i - pos
+}
+
+/// Replaces `memory.size` and `memory.grow` of the program with calls
+/// to generated functions that keep the `pages` of counts at the end
+/// of the memory and out of the program's sight. The `moved` globals
+/// hold addresses into the counter pages, the base of the counts first.
+fn hide_counter_pages(module: &mut Module, mem_id: MemoryId, moved: &[GlobalId], pages: u32) {
+    let size = add_size_func(module, mem_id, pages);
+    let grow = add_grow_func(module, mem_id, moved, pages);
+
+    let mut rewriter = MemoryRewriter { mem_id, size, grow };
+    module
+        .funcs
+        .iter_local_mut()
+        .filter(|(id, _)| *id != size && *id != grow)
+        .for_each(|(_, func)| {
+            walrus::ir::dfs_pre_order_mut(&mut rewriter, func, func.entry_block());
+        });
+}
+
+/// Adds a function returning the memory size minus the counter pages.
+fn add_size_func(module: &mut Module, mem_id: MemoryId, pages: u32) -> FunctionId {
+    let mut builder = FunctionBuilder::new(&mut module.types, &[], &[ValType::I32]);
+    builder.name("instrument_memory_size".to_string());
+    builder
+        .func_body()
+        .memory_size(mem_id)
+        .i32_const(pages as i32)
+        .binop(BinaryOp::I32Sub);
+
+    builder.finish(vec![], &mut module.funcs)
+}
+
+/// Adds a function growing the memory like `memory.grow` does, but
+/// moving the counter pages to the end of the grown memory. The pages
+/// left behind are zeroed as the program expects fresh pages there and
+/// the `moved` globals are updated, see `hide_counter_pages`. Only MVP
+/// instrs are used so no bulk memory support is required.
+fn add_grow_func(
+    module: &mut Module,
+    mem_id: MemoryId,
+    moved: &[GlobalId],
+    pages: u32,
+) -> FunctionId {
+    let base = moved[0];
+    let delta = module.locals.add(ValType::I32);
+    let old = module.locals.add(ValType::I32);
+    let index = module.locals.add(ValType::I32);
+    let addr = module.locals.add(ValType::I32);
+    let arg = MemArg {
+        align: COUNTSIZE as u32,
+        offset: 0,
+    };
+
+    let mut builder = FunctionBuilder::new(&mut module.types, &[ValType::I32], &[ValType::I32]);
+    builder.name("instrument_memory_grow".to_string());
+    let mut body = builder.func_body();
+
+    // Grow and bail out on failure
+    body.local_get(delta)
+        .memory_grow(mem_id)
+        .local_tee(old)
+        .i32_const(-1)
+        .binop(BinaryOp::I32Eq)
+        .if_else(
+            None,
+            |then| {
+                then.i32_const(-1).return_();
+            },
+            |_| {},
+        );
+
+    // Turn the page delta into bytes
+    body.local_get(delta)
+        .i32_const(PAGESIZE.trailing_zeros() as i32)
+        .binop(BinaryOp::I32Shl)
+        .local_set(delta);
+
+    // Move counts from the top down so none get overwritten before
+    // being copied, zeroing their old location
+    body.i32_const((pages as usize * PAGESIZE) as i32)
+        .local_set(index);
+    body.block(None, |done| {
+        let done_id = done.id();
+        done.loop_(None, |copy| {
+            let copy_id = copy.id();
+            copy.local_get(index)
+                .unop(UnaryOp::I32Eqz)
+                .br_if(done_id)
+                .local_get(index)
+                .i32_const(COUNTSIZE as i32)
+                .binop(BinaryOp::I32Sub)
+                .local_tee(index)
+                .global_get(base)
+                .binop(BinaryOp::I32Add)
+                .local_set(addr)
+                .local_get(addr)
+                .local_get(delta)
+                .binop(BinaryOp::I32Add)
+                .local_get(addr)
+                .load(mem_id, LoadKind::I32 { atomic: false }, arg)
+                .store(mem_id, StoreKind::I32 { atomic: false }, arg)
+                .local_get(addr)
+                .i32_const(0)
+                .store(mem_id, StoreKind::I32 { atomic: false }, arg)
+                .br(copy_id);
+        });
+    });
+
+    // Update the base (and the other addresses into the counter pages)
+    // and hide the counter pages from the result
+    for global in moved {
+        body.global_get(*global)
+            .local_get(delta)
+            .binop(BinaryOp::I32Add)
+            .global_set(*global);
+    }
+    body.local_get(old)
+        .i32_const(pages as i32)
+        .binop(BinaryOp::I32Sub);
+
+    builder.finish(vec![delta], &mut module.funcs)
+}
+
+/// Redirects `memory.size` and `memory.grow` of a memory to functions.
+struct MemoryRewriter {
+    mem_id: MemoryId,
+    size: FunctionId,
+    grow: FunctionId,
+}
+
+impl VisitorMut for MemoryRewriter {
+    fn visit_instr_mut(&mut self, instr: &mut Instr, _: &mut InstrLocId) {
+        let func = match instr {
+            Instr::MemorySize(size) if size.memory == self.mem_id => self.size,
+            Instr::MemoryGrow(grow) if grow.memory == self.mem_id => self.grow,
+            _ => return,
+        };
+
+        *instr = Instr::Call(Call { func });
+    }
+}
+
+/// Adds an exported i64 global for each of the `count` counts and
+/// replaces the calls to the placeholder imports `probe` and `branch`
+/// with increments of these globals. The imports are removed.
+fn add_global_counts(
+    module: &mut Module,
+    probe: FunctionId,
+    branch: FunctionId,
+    count: usize,
+) -> Vec<GlobalId> {
+    let counts: Vec<GlobalId> = (0..count)
+        .map(|id| {
+            let global =
+                module
+                    .globals
+                    .add_local(ValType::I64, true, InitExpr::Value(Value::I64(0)));
+            module.exports.add(
+                &format!("{}{}", COUNTPREFIX, id),
+                ExportItem::Global(global),
+            );
+            global
+        })
+        .collect();
+
+    if !counts.is_empty() {
+        let increment = add_increment_func(module, &counts);
+
+        let mut rewriter = GlobalsRewriter {
+            probe,
+            branch,
+            increment,
+            counts: &counts,
+            indexed: false,
+        };
+        module
+            .funcs
+            .iter_local_mut()
+            .filter(|(id, _)| *id != increment)
+            .for_each(|(_, func)| {
+                walrus::ir::dfs_pre_order_mut(&mut rewriter, func, func.entry_block());
+            });
+
+        if !rewriter.indexed {
+            module.funcs.delete(increment);
+        }
+    }
+
+    // Drop the placeholder imports
+    for func in [probe, branch] {
+        if let FunctionKind::Import(imported) = &module.funcs.get(func).kind {
+            module.imports.delete(imported.import);
+        }
+        module.funcs.delete(func);
+    }
+
+    counts
+}
+
+/// How `instrument_reset` clears the counts
+enum Reset {
+    /// Zero `size` bytes of memory from the address held by `base`, or
+    /// from address 0 without
+    Memory {
+        mem_id: MemoryId,
+        base: Option<GlobalId>,
+        size: usize,
+    },
+    /// Call the host, if it keeps any counts
+    Host(Option<FunctionId>),
+    /// Zero the globals
+    Globals(Vec<GlobalId>),
+}
+
+/// Adds the exported functions of the runtime switch: one setting the
+/// `enabled` global to its argument and one clearing the counts. Only
+/// MVP instrs are used, like in the other generated functions.
+fn add_switch_funcs(module: &mut Module, enabled: GlobalId, reset: Reset) {
+    let value = module.locals.add(ValType::I32);
+    let mut builder = FunctionBuilder::new(&mut module.types, &[ValType::I32], &[]);
+    builder.name(ENABLEFUNC.to_string());
+    builder.func_body().local_get(value).global_set(enabled);
+    let enable = builder.finish(vec![value], &mut module.funcs);
+    module.exports.add(ENABLEFUNC, ExportItem::Function(enable));
+
+    let index = module.locals.add(ValType::I32);
+    let mut builder = FunctionBuilder::new(&mut module.types, &[], &[]);
+    builder.name(RESETFUNC.to_string());
+    let mut body = builder.func_body();
+    match reset {
+        Reset::Memory { mem_id, base, size } => {
+            let arg = MemArg {
+                align: COUNTSIZE as u32,
+                offset: 0,
+            };
+
+            // Zero counts from the top down
+            body.i32_const(size as i32).local_set(index);
+            body.block(None, |done| {
+                let done_id = done.id();
+                done.loop_(None, |zero| {
+                    let zero_id = zero.id();
+                    zero.local_get(index)
+                        .unop(UnaryOp::I32Eqz)
+                        .br_if(done_id)
+                        .local_get(index)
+                        .i32_const(COUNTSIZE as i32)
+                        .binop(BinaryOp::I32Sub)
+                        .local_tee(index);
+                    if let Some(base) = base {
+                        zero.global_get(base).binop(BinaryOp::I32Add);
+                    }
+                    zero.i32_const(0)
+                        .store(mem_id, StoreKind::I32 { atomic: false }, arg)
+                        .br(zero_id);
+                });
+            });
+        }
+        Reset::Host(func) => {
+            if let Some(func) = func {
+                body.call(func);
+            }
+        }
+        Reset::Globals(counts) => {
+            for global in counts {
+                body.i64_const(0).global_set(global);
+            }
+        }
+    }
+    let reset = builder.finish(vec![], &mut module.funcs);
+    module.exports.add(RESETFUNC, ExportItem::Function(reset));
+}
+
+/// Adds a function incrementing the global of the count whose id is
+/// passed, dispatching on the id with a `br_table` out of nested blocks:
+/// leaving the block at depth `k` increments the count `k`.
+fn add_increment_func(module: &mut Module, counts: &[GlobalId]) -> FunctionId {
+    let id = module.locals.add(ValType::I32);
+
+    let mut builder = FunctionBuilder::new(&mut module.types, &[ValType::I32], &[]);
+    builder.name("instrument_count".to_string());
+    let blocks: Vec<_> = counts
+        .iter()
+        .map(|_| builder.dangling_instr_seq(None).id())
+        .collect();
+
+    // Innermost block dispatches on the id
+    builder
+        .instr_seq(blocks[0])
+        .local_get(id)
+        .instr(Instr::BrTable(BrTable {
+            blocks: blocks.clone().into_boxed_slice(),
+            default: blocks[blocks.len() - 1],
+        }));
+
+    // Every other block wraps the previous one, increments its count
+    // once it is left and returns
+    for k in 1..blocks.len() {
+        let mut block = builder.instr_seq(blocks[k]);
+        block.instr(Instr::Block(Block { seq: blocks[k - 1] }));
+        for instr in global_increment(counts[k - 1]) {
+            block.instr(instr);
+        }
+        block.instr(Instr::Return(Return {}));
+    }
+
+    let mut body = builder.func_body();
+    body.instr(Instr::Block(Block {
+        seq: blocks[blocks.len() - 1],
+    }));
+    for instr in global_increment(counts[counts.len() - 1]) {
+        body.instr(instr);
+    }
+
+    builder.finish(vec![id], &mut module.funcs)
+}
+
+/// Returns the instrs adding one to an i64 global.
+fn global_increment(global: GlobalId) -> [Instr; 4] {
+    [
+        Instr::GlobalGet(GlobalGet { global }),
+        Instr::Const(Const {
+            value: Value::I64(1),
+        }),
+        Instr::Binop(Binop {
+            op: BinaryOp::I64Add,
+        }),
+        Instr::GlobalSet(GlobalSet { global }),
+    ]
+}
+
+/// Replaces calls to the placeholder imports of the globals backend
+/// with increments of the count globals.
+struct GlobalsRewriter<'a> {
+    probe: FunctionId,
+    branch: FunctionId,
+    increment: FunctionId,
+    counts: &'a [GlobalId],
+    /// Whether any indexed increment needs the `increment` function
+    indexed: bool,
+}
+
+impl VisitorMut for GlobalsRewriter<'_> {
+    fn start_instr_seq_mut(&mut self, seq: &mut InstrSeq) {
+        let mut instrs = Vec::with_capacity(seq.instrs.len());
+        for (instr, loc) in seq.instrs.drain(..) {
+            match instr {
+                Instr::Call(Call { func }) if func == self.probe => {
+                    // The probe id is the const pushed right before
+                    let id = match instrs.pop() {
+                        Some((
+                            Instr::Const(Const {
+                                value: Value::I32(id),
+                            }),
+                            _,
+                        )) => id as usize,
+                        _ => unreachable!("Probe id must be pushed right before the call"),
+                    };
+                    instrs.extend(global_increment(self.counts[id]).map(|instr| (instr, loc)));
+                }
+                Instr::Call(Call { func }) if func == self.branch => {
+                    // Add the outcome to the id and dispatch at runtime
+                    instrs.push((
+                        Instr::Binop(Binop {
+                            op: BinaryOp::I32Add,
+                        }),
+                        loc,
+                    ));
+                    instrs.push((
+                        Instr::Call(Call {
+                            func: self.increment,
+                        }),
+                        loc,
+                    ));
+                    self.indexed = true;
+                }
+                instr => instrs.push((instr, loc)),
+            }
+        }
+        seq.instrs = instrs;
+    }
+}
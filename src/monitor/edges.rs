@@ -0,0 +1,96 @@
+use std::collections::BTreeMap;
+
+use walrus::{ir::InstrSeqId, LocalFunction, LocalId};
+
+use super::{cfg::Cfg, counters::Counters, Count, COUNTSIZE};
+
+/// Edge profile of a local function: only the chords of a spanning
+/// tree of its control flow graph get a counter, the counts of all
+/// other edges are derived from them by flow conservation. Derived
+/// counts are off for functions left by a trap or a call that never
+/// returns as flow is not conserved then.
+pub(crate) struct EdgeProfile {
+    pub(crate) cfg: Cfg,
+    /// Offset of the counter of every chord
+    counters: Vec<Option<usize>>,
+    /// Count of every edge as signed sum of counters
+    counts: Vec<Vec<(usize, i64)>>,
+}
+
+impl EdgeProfile {
+    /// Plans the counters of a function, placed from `foffset` on.
+    pub(crate) fn new(func: &LocalFunction, foffset: usize) -> EdgeProfile {
+        let cfg = Cfg::build(func);
+        let mut offset = foffset;
+        let counters: Vec<Option<usize>> = cfg
+            .chords()
+            .into_iter()
+            .map(|chord| {
+                chord.then(|| {
+                    offset += COUNTSIZE;
+                    offset - COUNTSIZE
+                })
+            })
+            .collect();
+        let counts = cfg.derive(&counters);
+
+        EdgeProfile {
+            cfg,
+            counters,
+            counts,
+        }
+    }
+
+    /// Size (in bytes) of the counters of the function
+    pub(crate) fn size(&self) -> usize {
+        self.counters.iter().flatten().count() * COUNTSIZE
+    }
+
+    /// Count of an edge
+    pub(crate) fn edge_count(&self, edge: usize) -> Count {
+        Count::from_terms(self.counts[edge].clone())
+    }
+
+    /// Count of the block holding the instr at `index` of `seq`, the
+    /// sum of the counts of the edges entering it.
+    pub(crate) fn block_count(&self, seq: InstrSeqId, index: usize) -> Count {
+        let block = self.cfg.block_at(seq, index).unwrap();
+        let mut count = BTreeMap::new();
+        for edge in self.cfg.predecessors(block) {
+            for (offset, factor) in &self.counts[edge] {
+                *count.entry(*offset).or_insert(0) += factor;
+            }
+        }
+        count.retain(|_, factor| *factor != 0);
+
+        Count::from_terms(count.into_iter().collect())
+    }
+
+    /// Counts of the outcomes of the branching instr at `index` of
+    /// `seq`, in the order of `Probe::outcome`.
+    pub(crate) fn outcome_counts(&self, seq: InstrSeqId, index: usize) -> Vec<Count> {
+        let block = self.cfg.block_at(seq, index).unwrap();
+        self.cfg
+            .successors(block)
+            .into_iter()
+            .map(|edge| self.edge_count(edge))
+            .collect()
+    }
+
+    /// Inserts the counters of the chords into the function. Branches
+    /// are counted conditionally, using `local_id` to keep their operand.
+    pub(crate) fn insert_counters(
+        &self,
+        func: &mut LocalFunction,
+        counters: &Counters,
+        local_id: LocalId,
+    ) {
+        let chords: Vec<usize> = (0..self.counters.len())
+            .filter(|edge| self.counters[*edge].is_some())
+            .collect();
+        self.cfg
+            .insert_on_edges(func, &chords, local_id, |instr_builder, pos, edge| {
+                counters.insert_increment(instr_builder, pos, self.counters[edge].unwrap());
+            });
+    }
+}
@@ -0,0 +1,135 @@
+use std::ops::RangeInclusive;
+
+use anyhow::bail;
+use regex::Regex;
+use walrus::{ExportItem, FunctionId, FunctionKind, Module};
+
+/// Selects the local functions monitors add probes to. A function is
+/// instrumented if it matches any of the `include` patterns (or there
+/// are none), none of the `exclude` patterns and is exported if
+/// `exported` is set.
+#[derive(Clone, Default)]
+pub struct Filter {
+    pub include: Vec<Pattern>,
+    pub exclude: Vec<Pattern>,
+    /// Only instrument functions exported by the module
+    pub exported: bool,
+}
+
+/// Matches functions by name (from the name section) or index
+#[derive(Clone)]
+pub enum Pattern {
+    /// Exact name
+    Name(String),
+    /// Regular expression searched in the name
+    Regex(Regex),
+    /// Inclusive range of function indices
+    Index(RangeInclusive<usize>),
+}
+
+impl Pattern {
+    /// Parses a pattern written as `#<index>` or `#<first>-<last>` for
+    /// function indices, `/<regex>/` for a regular expression, a glob
+    /// if it contains `*` or `?` and an exact name otherwise.
+    pub fn parse(pattern: &str) -> walrus::Result<Pattern> {
+        if let Some(indices) = pattern.strip_prefix('#') {
+            let (first, last) = indices.split_once('-').unwrap_or((indices, indices));
+            return match (first.parse(), last.parse()) {
+                (Ok(first), Ok(last)) if first <= last => Ok(Pattern::Index(first..=last)),
+                _ => bail!("Invalid function index range {}", pattern),
+            };
+        }
+
+        if let Some(regex) = pattern
+            .strip_prefix('/')
+            .and_then(|pattern| pattern.strip_suffix('/'))
+        {
+            return Ok(Pattern::Regex(Regex::new(regex)?));
+        }
+
+        if pattern.contains(['*', '?']) {
+            return Pattern::glob(pattern);
+        }
+
+        Ok(Pattern::Name(pattern.to_string()))
+    }
+
+    /// Matches whole names where `*` stands for any characters and `?`
+    /// for a single one.
+    pub fn glob(glob: &str) -> walrus::Result<Pattern> {
+        let regex = regex::escape(glob).replace(r"\*", ".*").replace(r"\?", ".");
+
+        Ok(Pattern::Regex(Regex::new(&format!("^{}$", regex))?))
+    }
+
+    fn matches(&self, index: usize, name: Option<&str>) -> bool {
+        match (self, name) {
+            (Pattern::Name(pattern), Some(name)) => pattern == name,
+            (Pattern::Regex(regex), Some(name)) => regex.is_match(name),
+            (Pattern::Index(range), _) => range.contains(&index),
+            (_, None) => false,
+        }
+    }
+}
+
+impl Filter {
+    /// Whether the filter lets every function through
+    pub fn is_empty(&self) -> bool {
+        self.include.is_empty() && self.exclude.is_empty() && !self.exported
+    }
+
+    /// Whether probes are added to `func`.
+    pub fn selects(&self, module: &Module, func: FunctionId) -> bool {
+        let index = func.index();
+        let name = module.funcs.get(func).name.as_deref();
+        let matches =
+            |patterns: &[Pattern]| patterns.iter().any(|pattern| pattern.matches(index, name));
+
+        (self.include.is_empty() || matches(&self.include))
+            && !matches(&self.exclude)
+            && (!self.exported
+                || module
+                    .exports
+                    .iter()
+                    .any(|export| matches!(export.item, ExportItem::Function(id) if id == func)))
+    }
+
+    /// Detaches the local functions not selected from the module so
+    /// monitors skip them, until they are restored. Their ids stay
+    /// valid, so calls to them can still be probed.
+    pub(crate) fn detach(&self, module: &mut Module) -> Detached {
+        let mut detached = Detached { funcs: vec![] };
+        if self.is_empty() {
+            return detached;
+        }
+
+        let ids: Vec<FunctionId> = module
+            .funcs
+            .iter_local()
+            .map(|(id, _)| id)
+            .filter(|id| !self.selects(module, *id))
+            .collect();
+        for id in ids {
+            let func = module.funcs.get_mut(id);
+            let ty = func.ty();
+            let kind = std::mem::replace(&mut func.kind, FunctionKind::Uninitialized(ty));
+            detached.funcs.push((id, kind));
+        }
+
+        detached
+    }
+}
+
+/// Local functions detached by a `Filter`
+pub(crate) struct Detached {
+    funcs: Vec<(FunctionId, FunctionKind)>,
+}
+
+impl Detached {
+    /// Puts the detached functions back into the module.
+    pub(crate) fn restore(self, module: &mut Module) {
+        for (id, kind) in self.funcs {
+            module.funcs.get_mut(id).kind = kind;
+        }
+    }
+}
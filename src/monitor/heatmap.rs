@@ -0,0 +1,163 @@
+use anyhow::bail;
+use walrus::{
+    ir::{BinaryOp, Instr, InstrSeqId, Value},
+    InstrSeqBuilder, LocalFunction, LocalId, MemoryId, Module, ValType,
+};
+
+use super::{
+    counters::Counters,
+    memory::{memory_op, Locals},
+    sites::{instrument_sites, Sites},
+    Count, Probe, COUNTSIZE, PAGESIZE,
+};
+
+/// Most buckets a memory may be split into
+const MAXBUCKETS: usize = 65536;
+
+/// Largest bucket, an i32 shift by 32 would leave addresses alone
+const MAXBUCKETSIZE: usize = 1 << 31;
+
+/// Loads and stores of the main memory probed by the heatmap monitor
+struct BucketSites {
+    /// Locals for the address and the operands above it
+    locals: Locals,
+    /// Local for the bucket index
+    bucket: LocalId,
+    mem_id: MemoryId,
+    /// Bits to shift an address by to get its bucket
+    shift: u32,
+    nbuckets: usize,
+}
+
+/// Adds memory heatmap instrumentation logic to a module.
+///     1.  Counts are kept wherever the provided `Counters` put them
+///     2.  The main (first) memory of the module is split into buckets
+///         of `bucket_size` bytes, enough to cover its initial size but
+///         at most `MAXBUCKETS`, each with a counter. One more counter
+///         takes accesses past the last bucket, e.g. to pages added by
+///         `memory.grow`. Modules without a memory of their own get no
+///         probes.
+///     3.  Every load and store (including SIMD and atomic ones) of
+///         the main memory is instrumented by `instrument_sites` to
+///         increment the counter of the bucket holding its effective
+///         address.
+///     4.  Record a `Probe` for every bucket so the captured memory
+///         can be decoded afterwards. As buckets are shared by all
+///         functions they are attributed to the first local function.
+///
+/// Returns the probes and the size (in bytes) of their counters.
+/// Fails if `bucket_size` is not a power of two up to 2 GiB.
+pub fn instrument(
+    module: &mut Module,
+    counters: &Counters,
+    bucket_size: usize,
+) -> walrus::Result<(Vec<Probe>, usize)> {
+    if !bucket_size.is_power_of_two() {
+        bail!("Bucket size {} is not a power of two", bucket_size);
+    }
+    if bucket_size > MAXBUCKETSIZE {
+        bail!(
+            "Bucket size {} is larger than {} bytes",
+            bucket_size,
+            MAXBUCKETSIZE
+        );
+    }
+
+    // Skip the memory holding the counts
+    let memory = module
+        .memories
+        .iter()
+        .find(|memory| Some(memory.id()) != counters.memory());
+    let memory = match memory {
+        Some(memory) => memory,
+        None => return Ok((vec![], 0)),
+    };
+    let mem_id = memory.id();
+    let nbuckets = (memory.initial as usize * PAGESIZE)
+        .div_ceil(bucket_size)
+        .min(MAXBUCKETS);
+
+    // Create local vars for the address and the operands above it,
+    // and one for the bucket index. Sites share the bucket counters
+    // and keep none of their own.
+    let sites = BucketSites {
+        locals: Locals::add(module),
+        bucket: module.locals.add(ValType::I32),
+        mem_id,
+        shift: bucket_size.trailing_zeros(),
+        nbuckets,
+    };
+    instrument_sites(module, counters, &sites)?;
+
+    let first = module.funcs.iter_local().next().map(|(id, _)| id);
+    let probes = match first {
+        Some(func) => (0..=nbuckets)
+            .map(|outcome| Probe {
+                count: Count::Counter(outcome * COUNTSIZE),
+                func,
+                seq_path: vec![],
+                index: 0,
+                opcode: "bucket".to_string(),
+                outcome,
+                path: vec![],
+                callee: None,
+                backward: false,
+            })
+            .collect(),
+        None => vec![],
+    };
+    let size = probes.len() * COUNTSIZE;
+
+    Ok((probes, size))
+}
+
+impl Sites for BucketSites {
+    fn select(
+        &self,
+        _func: &LocalFunction,
+        _seq_path: &[InstrSeqId],
+        _index: usize,
+        instr: &Instr,
+    ) -> Option<usize> {
+        match memory_op(instr) {
+            Some(op) if op.memory == self.mem_id => Some(0),
+            _ => None,
+        }
+    }
+
+    fn emit(
+        &self,
+        instr_builder: &mut InstrSeqBuilder,
+        pos: usize,
+        instr: &Instr,
+        _offset: usize,
+        counters: &Counters,
+    ) -> walrus::Result<usize> {
+        let mut i = pos;
+        let op = memory_op(instr).unwrap();
+
+        // Save the operands to compute the effective address
+        i += self.locals.insert_save(instr_builder, i, &op);
+        i += self.locals.insert_address(instr_builder, i, &op, 0);
+
+        // Clamp the bucket of the address to the one past the
+        // last to get the index of the counter to increment
+        let last = self.nbuckets as i32;
+        instr_builder
+            .const_at(i, Value::I32(self.shift as i32))
+            .binop_at(i + 1, BinaryOp::I32ShrU)
+            .local_tee_at(i + 2, self.bucket)
+            .const_at(i + 3, Value::I32(last))
+            .local_get_at(i + 4, self.bucket)
+            .const_at(i + 5, Value::I32(last))
+            .binop_at(i + 6, BinaryOp::I32LtU)
+            .select_at(i + 7, None);
+        i += 8;
+
+        // Insert counting instrs
+        i += counters.insert_indexed_increment(instr_builder, i, 0);
+        i += self.locals.insert_restore(instr_builder, i, &op);
+
+        Ok(i - pos)
+    }
+}
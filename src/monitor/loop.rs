@@ -0,0 +1,67 @@
+use walrus::{
+    ir::{Instr, InstrSeqId},
+    InstrSeqBuilder, LocalFunction, Module,
+};
+
+use super::{
+    counters::Counters,
+    sites::{instrument_sites, Sites},
+    Probe, COUNTSIZE,
+};
+
+/// Loops probed by the loop monitor
+struct LoopSites;
+
+/// Adds loop instrumentation logic to a module.
+///     1.  Counts are kept wherever the provided `Counters` put them
+///     2.  Loops are instrumented by `instrument_sites`. Every loop gets
+///         two counters: the number of times the loop is entered and
+///         the number of times its body starts, i.e. its iterations.
+///         Back-edge executions are the difference and the average
+///         trip count is iterations / entries.
+///     3.  Entries are counted right before the `loop` instr, iterations
+///         at the start of its body which is where back-edges jump to.
+///     4.  Record a `Probe` for every counter so the captured memory
+///         can be decoded afterwards.
+///
+/// Returns the probes and the size (in bytes) of their counters.
+pub fn instrument(module: &mut Module, counters: &Counters) -> walrus::Result<(Vec<Probe>, usize)> {
+    instrument_sites(module, counters, &LoopSites)
+}
+
+impl Sites for LoopSites {
+    fn select(
+        &self,
+        _func: &LocalFunction,
+        _seq_path: &[InstrSeqId],
+        _index: usize,
+        instr: &Instr,
+    ) -> Option<usize> {
+        // Entry (outcome 0) and iteration (outcome 1) counters
+        match instr {
+            Instr::Loop(_) => Some(2),
+            _ => None,
+        }
+    }
+
+    fn emit(
+        &self,
+        instr_builder: &mut InstrSeqBuilder,
+        pos: usize,
+        instr: &Instr,
+        offset: usize,
+        counters: &Counters,
+    ) -> walrus::Result<usize> {
+        let body = match instr {
+            Instr::Loop(block) => block.seq,
+            _ => unreachable!("Only loops are selected"),
+        };
+
+        // Count iterations at the loop header
+        let mut body_builder = instr_builder.instr_seq(body);
+        counters.insert_increment(&mut body_builder, 0, offset + COUNTSIZE);
+
+        // Count entries right before the loop
+        Ok(counters.insert_increment(instr_builder, pos, offset))
+    }
+}
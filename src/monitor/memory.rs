@@ -0,0 +1,333 @@
+use walrus::{
+    ir::{AtomicWidth, BinaryOp, Instr, InstrSeqId, LoadSimdKind, StoreKind, Value},
+    FunctionId, InstrSeqBuilder, LocalFunction, LocalId, MemoryId, Module, ValType,
+};
+
+use super::{
+    counters::Counters,
+    sites::{instrument_sites, Sites},
+    Access, Count, Probe, COUNTSIZE, HOSTMODULE,
+};
+
+/// Value types of the operands saved to locals
+pub(crate) const OPERAND_TYPES: [ValType; 5] = [
+    ValType::I32,
+    ValType::I64,
+    ValType::F32,
+    ValType::F64,
+    ValType::V128,
+];
+
+/// How a load or store instr accesses memory
+#[derive(Debug)]
+pub(crate) struct MemoryOp {
+    /// Memory accessed
+    pub(crate) memory: MemoryId,
+    /// Types of the operands above the address on the stack, bottom first
+    pub(crate) operands: Vec<ValType>,
+    /// Constant offset added to the address
+    pub(crate) offset: u32,
+    /// Number of bytes accessed
+    pub(crate) size: u32,
+}
+
+/// Loads and stores probed by the memory monitor
+struct MemorySites {
+    locals: Locals,
+    access: Access,
+    /// Imported function accesses are reported to when tracing
+    trace: Option<FunctionId>,
+}
+
+/// Locals used to capture addresses
+pub(crate) struct Locals {
+    /// Address operand of the access
+    pub(crate) address: LocalId,
+    /// Locals holding the operands above the address, one list per
+    /// type in `OPERAND_TYPES` with a local per operand position
+    operands: Vec<Vec<LocalId>>,
+}
+
+impl Locals {
+    /// Adds the locals to the module.
+    pub(crate) fn add(module: &mut Module) -> Locals {
+        Locals {
+            address: module.locals.add(ValType::I32),
+            operands: OPERAND_TYPES
+                .iter()
+                .map(|ty| (0..2).map(|_| module.locals.add(*ty)).collect())
+                .collect(),
+        }
+    }
+
+    fn operand(&self, ty: ValType, position: usize) -> LocalId {
+        let i = OPERAND_TYPES.iter().position(|t| *t == ty).unwrap();
+        self.operands[i][position]
+    }
+
+    /// Inserts instrs popping the operands of a memory op, including
+    /// the address, into the locals. Returns the number of inserted
+    /// instrs.
+    pub(crate) fn insert_save(
+        &self,
+        instr_builder: &mut InstrSeqBuilder,
+        pos: usize,
+        op: &MemoryOp,
+    ) -> usize {
+        let mut i = pos;
+        for (position, ty) in op.operands.iter().enumerate().rev() {
+            instr_builder.local_set_at(i, self.operand(*ty, position));
+            i += 1;
+        }
+        instr_builder.local_set_at(i, self.address);
+
+        i + 1 - pos
+    }
+
+    /// Inserts instrs pushing the operands saved by `insert_save` back
+    /// onto the stack. Returns the number of inserted instrs.
+    pub(crate) fn insert_restore(
+        &self,
+        instr_builder: &mut InstrSeqBuilder,
+        pos: usize,
+        op: &MemoryOp,
+    ) -> usize {
+        instr_builder.local_get_at(pos, self.address);
+        for (position, ty) in op.operands.iter().enumerate() {
+            instr_builder.local_get_at(pos + 1 + position, self.operand(*ty, position));
+        }
+
+        1 + op.operands.len()
+    }
+
+    /// Inserts instrs pushing the effective address of a memory op
+    /// saved by `insert_save` plus `extra` bytes. Returns the number
+    /// of inserted instrs.
+    pub(crate) fn insert_address(
+        &self,
+        instr_builder: &mut InstrSeqBuilder,
+        pos: usize,
+        op: &MemoryOp,
+        extra: u32,
+    ) -> usize {
+        instr_builder
+            .local_get_at(pos, self.address)
+            .const_at(pos + 1, Value::I32(op.offset.wrapping_add(extra) as i32))
+            .binop_at(pos + 2, BinaryOp::I32Add);
+
+        3
+    }
+}
+
+/// Adds memory access instrumentation logic to a module.
+///     1.  Counts are kept wherever the provided `Counters` put them
+///     2.  Loads and stores are instrumented by `instrument_sites`.
+///         Every one (including SIMD and atomic ones) gets a counter
+///         for its accesses.
+///     3.  With `Access::Range` the operands above the address are
+///         saved to locals to copy the address. The highest end and
+///         the complement of the lowest effective address (address
+///         plus the constant offset) are kept in two more counters.
+///     4.  With `Access::Trace` the effective address and access size
+///         are reported to the imported `instr.access(i32 id, i32
+///         address, i32 size)` on every access, with `id` the counter
+///         offset divided by the count size.
+///     5.  Record a `Probe` for every counter so the captured memory
+///         can be decoded afterwards.
+///
+/// Returns the probes and the size (in bytes) of their counters.
+/// Fails for address ranges with backends that can only count.
+pub fn instrument(
+    module: &mut Module,
+    counters: &Counters,
+    access: Access,
+) -> walrus::Result<(Vec<Probe>, usize)> {
+    // Create local vars for the address and the operands above it
+    let locals = Locals::add(module);
+    let trace = match access {
+        Access::Trace => {
+            let ty = module
+                .types
+                .add(&[ValType::I32, ValType::I32, ValType::I32], &[]);
+            Some(module.add_import_func(HOSTMODULE, "access", ty).0)
+        }
+        _ => None,
+    };
+
+    let sites = MemorySites {
+        locals,
+        access,
+        trace,
+    };
+    instrument_sites(module, counters, &sites)
+}
+
+/// Returns how an instr accesses memory, if it does.
+pub(crate) fn memory_op(instr: &Instr) -> Option<MemoryOp> {
+    let (memory, operands, arg, size) = match instr {
+        Instr::Load(load) => (load.memory, vec![], load.arg, load.kind.width()),
+        Instr::Store(store) => (
+            store.memory,
+            vec![store_type(store.kind)],
+            store.arg,
+            store.kind.width(),
+        ),
+        Instr::LoadSimd(load) => {
+            let (operands, size) = match load.kind {
+                LoadSimdKind::Splat8 => (vec![], 1),
+                LoadSimdKind::Splat16 => (vec![], 2),
+                LoadSimdKind::Splat32 | LoadSimdKind::V128Load32Zero => (vec![], 4),
+                LoadSimdKind::Splat64
+                | LoadSimdKind::V128Load8x8S
+                | LoadSimdKind::V128Load8x8U
+                | LoadSimdKind::V128Load16x4S
+                | LoadSimdKind::V128Load16x4U
+                | LoadSimdKind::V128Load32x2S
+                | LoadSimdKind::V128Load32x2U
+                | LoadSimdKind::V128Load64Zero => (vec![], 8),
+                LoadSimdKind::V128Load8Lane(_) | LoadSimdKind::V128Store8Lane(_) => {
+                    (vec![ValType::V128], 1)
+                }
+                LoadSimdKind::V128Load16Lane(_) | LoadSimdKind::V128Store16Lane(_) => {
+                    (vec![ValType::V128], 2)
+                }
+                LoadSimdKind::V128Load32Lane(_) | LoadSimdKind::V128Store32Lane(_) => {
+                    (vec![ValType::V128], 4)
+                }
+                LoadSimdKind::V128Load64Lane(_) | LoadSimdKind::V128Store64Lane(_) => {
+                    (vec![ValType::V128], 8)
+                }
+            };
+            (load.memory, operands, load.arg, size)
+        }
+        Instr::AtomicRmw(rmw) => (
+            rmw.memory,
+            vec![atomic_type(rmw.width)],
+            rmw.arg,
+            rmw.width.bytes(),
+        ),
+        Instr::Cmpxchg(cmpxchg) => (
+            cmpxchg.memory,
+            vec![atomic_type(cmpxchg.width); 2],
+            cmpxchg.arg,
+            cmpxchg.width.bytes(),
+        ),
+        Instr::AtomicNotify(notify) => (notify.memory, vec![ValType::I32], notify.arg, 4),
+        Instr::AtomicWait(wait) => match wait.sixty_four {
+            true => (wait.memory, vec![ValType::I64, ValType::I64], wait.arg, 8),
+            false => (wait.memory, vec![ValType::I32, ValType::I64], wait.arg, 4),
+        },
+        _ => return None,
+    };
+
+    Some(MemoryOp {
+        memory,
+        operands,
+        offset: arg.offset,
+        size,
+    })
+}
+
+/// Type of the value stored by a store instr
+fn store_type(kind: StoreKind) -> ValType {
+    match kind {
+        StoreKind::I32 { .. } | StoreKind::I32_8 { .. } | StoreKind::I32_16 { .. } => ValType::I32,
+        StoreKind::I64 { .. }
+        | StoreKind::I64_8 { .. }
+        | StoreKind::I64_16 { .. }
+        | StoreKind::I64_32 { .. } => ValType::I64,
+        StoreKind::F32 => ValType::F32,
+        StoreKind::F64 => ValType::F64,
+        StoreKind::V128 => ValType::V128,
+    }
+}
+
+/// Type of the operands of an atomic instr
+fn atomic_type(width: AtomicWidth) -> ValType {
+    match width {
+        AtomicWidth::I32 | AtomicWidth::I32_8 | AtomicWidth::I32_16 => ValType::I32,
+        AtomicWidth::I64 | AtomicWidth::I64_8 | AtomicWidth::I64_16 | AtomicWidth::I64_32 => {
+            ValType::I64
+        }
+    }
+}
+
+impl Sites for MemorySites {
+    fn select(
+        &self,
+        _func: &LocalFunction,
+        _seq_path: &[InstrSeqId],
+        _index: usize,
+        instr: &Instr,
+    ) -> Option<usize> {
+        // Count accesses, then highest end and lowest address
+        memory_op(instr).map(|_| match self.access {
+            Access::Range => 3,
+            Access::Count | Access::Trace => 1,
+        })
+    }
+
+    fn emit(
+        &self,
+        instr_builder: &mut InstrSeqBuilder,
+        pos: usize,
+        instr: &Instr,
+        offset: usize,
+        counters: &Counters,
+    ) -> walrus::Result<usize> {
+        let mut i = pos;
+        let op = memory_op(instr).unwrap();
+        let locals = &self.locals;
+
+        // Insert counting instrs
+        i += counters.insert_increment(instr_builder, i, offset);
+
+        if matches!(self.access, Access::Count) {
+            return Ok(i - pos);
+        }
+
+        i += locals.insert_save(instr_builder, i, &op);
+        match (self.access, self.trace) {
+            (Access::Trace, Some(trace)) => {
+                let id = counters.id(offset) as i32;
+                i += counters.insert_recording(instr_builder, i, |instr_builder, pos| {
+                    instr_builder.const_at(pos, Value::I32(id));
+                    let mut i = pos + 1;
+                    i += locals.insert_address(instr_builder, i, &op, 0);
+                    instr_builder
+                        .const_at(i, Value::I32(op.size as i32))
+                        .call_at(i + 1, trace);
+                    i + 2 - pos
+                });
+            }
+            _ => {
+                i += locals.insert_address(instr_builder, i, &op, op.size);
+                i += counters.insert_max(instr_builder, i, offset + COUNTSIZE)?;
+
+                // The highest complement is the lowest address
+                i += locals.insert_address(instr_builder, i, &op, 0);
+                instr_builder
+                    .const_at(i, Value::I32(-1))
+                    .binop_at(i + 1, BinaryOp::I32Xor);
+                i += 2;
+                i += counters.insert_max(instr_builder, i, offset + 2 * COUNTSIZE)?;
+            }
+        }
+        i += locals.insert_restore(instr_builder, i, &op);
+
+        Ok(i - pos)
+    }
+
+    fn describe(
+        &self,
+        _func: &LocalFunction,
+        _seq_path: &[InstrSeqId],
+        _instr: &Instr,
+        probe: &mut Probe,
+    ) {
+        // The highest end and lowest address are no counts
+        if let (Count::Counter(offset), 1..) = (&probe.count, probe.outcome) {
+            probe.count = Count::Value(*offset);
+        }
+    }
+}
@@ -0,0 +1,108 @@
+use std::collections::BTreeMap;
+
+use walrus::{
+    ir::{Instr, InstrLocId, InstrSeqId, Visitor},
+    InstrSeqBuilder, LocalFunction, Module,
+};
+
+use super::{
+    counters::Counters,
+    opcode,
+    sites::{instrument_sites, Sites},
+    Count, Probe, COUNTSIZE,
+};
+
+/// Instructions probed by the opcode mix monitor, all of them
+struct OpcodeSites {
+    /// Index of the counter of every opcode class
+    classes: BTreeMap<String, usize>,
+}
+
+/// Adds opcode mix instrumentation logic to a module.
+///     1.  Counts are kept wherever the provided `Counters` put them
+///     2.  Every opcode class occurring in the module gets a counter,
+///         in the order of their names. Binary and unary operations
+///         are classed by their operator.
+///     3.  Every instruction (including `block`, `loop` and `if`)
+///         increments the counter of its class, so the counts add up
+///         to the number of instructions executed.
+///     4.  Record a `Probe` for every class so the captured memory
+///         can be decoded afterwards. As classes are shared by all
+///         functions they are attributed to the first local function.
+///
+/// Returns the probes and the size (in bytes) of their counters.
+pub fn instrument(module: &mut Module, counters: &Counters) -> walrus::Result<(Vec<Probe>, usize)> {
+    // Number the classes once all are known
+    let mut collector = ClassCollector::default();
+    for (_, func) in module.funcs.iter_local() {
+        walrus::ir::dfs_in_order(&mut collector, func, func.entry_block());
+    }
+    let mut classes = collector.classes;
+    for (index, class) in classes.values_mut().enumerate() {
+        *class = index;
+    }
+
+    // Sites share the class counters and keep none of their own
+    let sites = OpcodeSites { classes };
+    instrument_sites(module, counters, &sites)?;
+
+    let first = module.funcs.iter_local().next().map(|(id, _)| id);
+    let probes: Vec<Probe> = match first {
+        Some(func) => sites
+            .classes
+            .into_iter()
+            .map(|(opcode, outcome)| Probe {
+                count: Count::Counter(outcome * COUNTSIZE),
+                func,
+                seq_path: vec![],
+                index: 0,
+                opcode,
+                outcome,
+                path: vec![],
+                callee: None,
+                backward: false,
+            })
+            .collect(),
+        None => vec![],
+    };
+    let size = probes.len() * COUNTSIZE;
+
+    Ok((probes, size))
+}
+
+/// Collects the opcode classes of the instrs visited
+#[derive(Default)]
+struct ClassCollector {
+    classes: BTreeMap<String, usize>,
+}
+
+impl Visitor<'_> for ClassCollector {
+    fn visit_instr(&mut self, instr: &Instr, _: &InstrLocId) {
+        self.classes.entry(opcode(instr)).or_default();
+    }
+}
+
+impl Sites for OpcodeSites {
+    fn select(
+        &self,
+        _func: &LocalFunction,
+        _seq_path: &[InstrSeqId],
+        _index: usize,
+        _instr: &Instr,
+    ) -> Option<usize> {
+        Some(0)
+    }
+
+    fn emit(
+        &self,
+        instr_builder: &mut InstrSeqBuilder,
+        pos: usize,
+        instr: &Instr,
+        _offset: usize,
+        counters: &Counters,
+    ) -> walrus::Result<usize> {
+        // Insert counting instrs
+        let offset = self.classes[&opcode(instr)] * COUNTSIZE;
+        Ok(counters.insert_increment(instr_builder, pos, offset))
+    }
+}
@@ -0,0 +1,259 @@
+use walrus::{
+    ir::{BinaryOp, Value},
+    FunctionId, LocalFunction, LocalId, Module, ValType,
+};
+
+use super::{
+    cfg::{Cfg, ENTRY, EXIT},
+    counters::Counters,
+    Count, Probe, Span, COUNTSIZE, SKIPPED,
+};
+
+/// Most paths a single function may have
+const MAXPATHS: u64 = 4096;
+
+/// Edge of the acyclic graph paths are numbered on
+struct DagEdge {
+    from: usize,
+    to: usize,
+    /// Edge of the control flow graph the edge stands for
+    edge: usize,
+    /// Whether it is one of the dummy edges replacing a back edge
+    dummy: bool,
+}
+
+/// Adds Ball-Larus path profiling logic to a module.
+///     1.  Counts are kept wherever the provided `Counters` put them
+///     2.  The control flow graph of every local function is made
+///         acyclic by replacing every back edge `v -> w` with dummy
+///         edges `ENTRY -> w` and `v -> EXIT`.
+///     3.  Every edge gets a value such that the sum of the values
+///         along a path from `ENTRY` to `EXIT` is a unique path id
+///         between 0 and the number of paths. A function with `n`
+///         paths reserves `n * SIZE` bytes for their counts.
+///     4.  The sum is kept in a local path register, incremented on
+///         the edges. Returns increment the count of the path in the
+///         register, back edges too before resetting the register to
+///         the value of the dummy edge into the loop.
+///     5.  Record a `Probe` with the blocks along every path so the
+///         captured memory can be decoded afterwards.
+///
+/// Returns the probes and the size (in bytes) of their counters.
+/// Functions with more than `MAXPATHS` paths are skipped, they get a
+/// single probe with the opcode `SKIPPED` and no counter instead.
+/// Its outcome is their number of paths.
+pub fn instrument(module: &mut Module, counters: &Counters) -> (Vec<Probe>, usize) {
+    // Create local vars for the path register and to copy branch operands
+    let register = module.locals.add(ValType::I32);
+    let local_id = module.locals.add(ValType::I32);
+
+    // Iterate on local functions
+    let mut curr_foffset = 0;
+    let mut probes: Vec<Probe> = Vec::new();
+    for (id, func) in module.funcs.iter_local_mut() {
+        curr_foffset += instrument_func(
+            id,
+            func,
+            curr_foffset,
+            counters,
+            register,
+            local_id,
+            &mut probes,
+        );
+    }
+
+    (probes, curr_foffset)
+}
+
+/// Instrument a local function and return size (in bytes)
+/// of memory it will require to capture its instrumentation data
+/// (none if it has too many paths to be profiled)
+fn instrument_func(
+    func_id: FunctionId,
+    func: &mut LocalFunction,
+    foffset: usize,
+    counters: &Counters,
+    register: LocalId,
+    local_id: LocalId,
+    probes: &mut Vec<Probe>,
+) -> usize {
+    let cfg = Cfg::build(func);
+
+    // Replace back edges, leaving out the virtual edge
+    let mut dag: Vec<DagEdge> = Vec::new();
+    for (i, edge) in cfg.edges.iter().enumerate().skip(1) {
+        match edge.back {
+            true => {
+                dag.push(DagEdge {
+                    from: ENTRY,
+                    to: edge.to,
+                    edge: i,
+                    dummy: true,
+                });
+                dag.push(DagEdge {
+                    from: edge.from,
+                    to: EXIT,
+                    edge: i,
+                    dummy: true,
+                });
+            }
+            false => dag.push(DagEdge {
+                from: edge.from,
+                to: edge.to,
+                edge: i,
+                dummy: false,
+            }),
+        }
+    }
+
+    let num_paths = count_paths(&cfg, &dag);
+    if num_paths[ENTRY] > MAXPATHS {
+        probes.push(Probe {
+            count: Count::Derived(vec![]),
+            func: func_id,
+            seq_path: vec![func.entry_block()],
+            index: 0,
+            opcode: SKIPPED.to_string(),
+            outcome: num_paths[ENTRY] as usize,
+            path: vec![],
+            callee: None,
+            backward: false,
+        });
+        return 0;
+    }
+
+    // Number the edges leaving every block one after the other
+    let mut values: Vec<u64> = vec![0; dag.len()];
+    let mut next_value = vec![0; cfg.blocks.len()];
+    for (i, edge) in dag.iter().enumerate() {
+        values[i] = next_value[edge.from];
+        next_value[edge.from] = values[i].saturating_add(num_paths[edge.to]);
+    }
+
+    // Record the blocks along every path
+    for id in 0..num_paths[ENTRY] {
+        let path = decode_path(&dag, &values, &num_paths, id);
+        let first = &cfg.blocks[path[0]];
+        probes.push(Probe {
+            count: Count::Counter(foffset + id as usize * COUNTSIZE),
+            func: func_id,
+            seq_path: first.seq_path.clone(),
+            index: first.start,
+            opcode: "path".to_string(),
+            outcome: id as usize,
+            path: path
+                .iter()
+                .map(|block| Span {
+                    seq: cfg.blocks[*block].seq(),
+                    start: cfg.blocks[*block].start,
+                    end: cfg.blocks[*block].end,
+                })
+                .collect(),
+            callee: None,
+            backward: false,
+        });
+    }
+
+    // Work out what every edge does to the path register: add its
+    // value, count the path and reset the register for the next one
+    let mut actions: Vec<(u64, bool, Option<u64>)> = vec![(0, false, None); cfg.edges.len()];
+    for (i, edge) in dag.iter().enumerate() {
+        let action = &mut actions[edge.edge];
+        match (edge.dummy, edge.from) {
+            (true, ENTRY) => action.2 = Some(values[i]),
+            _ => action.0 = values[i],
+        }
+        action.1 |= edge.to == EXIT;
+    }
+    let edges: Vec<usize> = (0..cfg.edges.len())
+        .filter(|edge| actions[*edge] != (0, false, None))
+        .collect();
+
+    cfg.insert_on_edges(func, &edges, local_id, |instr_builder, pos, edge| {
+        let (value, count, reset) = actions[edge];
+        let mut i = pos;
+        if value != 0 {
+            instr_builder
+                .local_get_at(i, register)
+                .const_at(i + 1, Value::I32(value as i32))
+                .binop_at(i + 2, BinaryOp::I32Add)
+                .local_set_at(i + 3, register);
+            i += 4;
+        }
+
+        if count {
+            instr_builder.local_get_at(i, register);
+            i += 1;
+            i += counters.insert_indexed_increment(instr_builder, i, foffset);
+        }
+
+        if let Some(value) = reset {
+            instr_builder
+                .const_at(i, Value::I32(value as i32))
+                .local_set_at(i + 1, register);
+        }
+    });
+
+    num_paths[ENTRY] as usize * COUNTSIZE
+}
+
+/// Counts the paths from every block to `EXIT` in the acyclic graph.
+fn count_paths(cfg: &Cfg, dag: &[DagEdge]) -> Vec<u64> {
+    let mut successors: Vec<Vec<usize>> = vec![vec![]; cfg.blocks.len()];
+    for edge in dag {
+        successors[edge.from].push(edge.to);
+    }
+
+    // Successors are counted before the blocks leading to them
+    let mut num_paths: Vec<Option<u64>> = vec![None; cfg.blocks.len()];
+    num_paths[EXIT] = Some(1);
+    let mut stack: Vec<usize> = (0..cfg.blocks.len()).collect();
+    while let Some(&block) = stack.last() {
+        if num_paths[block].is_some() {
+            stack.pop();
+            continue;
+        }
+
+        let pending: Vec<usize> = successors[block]
+            .iter()
+            .copied()
+            .filter(|to| num_paths[*to].is_none())
+            .collect();
+        match pending.is_empty() {
+            true => {
+                num_paths[block] = Some(successors[block].iter().fold(0, |sum: u64, to| {
+                    sum.saturating_add(num_paths[*to].unwrap())
+                }));
+                stack.pop();
+            }
+            false => stack.extend(pending),
+        }
+    }
+
+    num_paths.into_iter().map(Option::unwrap).collect()
+}
+
+/// Returns the blocks along the path with the given id. Paths starting
+/// with a dummy edge start at the loop it enters.
+fn decode_path(dag: &[DagEdge], values: &[u64], num_paths: &[u64], id: u64) -> Vec<usize> {
+    let mut path = Vec::new();
+    let mut block = ENTRY;
+    let mut rest = id;
+    while block != EXIT {
+        // The edge whose range of path ids holds the rest
+        let i = (0..dag.len())
+            .find(|i| {
+                let edge = &dag[*i];
+                edge.from == block && values[*i] <= rest && rest - values[*i] < num_paths[edge.to]
+            })
+            .unwrap();
+        if !(block == ENTRY && dag[i].dummy) {
+            path.push(block);
+        }
+
+        rest -= values[i];
+        block = dag[i].to;
+    }
+
+    path
+}
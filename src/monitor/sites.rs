@@ -0,0 +1,250 @@
+use walrus::{
+    ir::{Instr, InstrSeqId},
+    FunctionId, InstrSeqBuilder, LocalFunction, Module,
+};
+
+use super::{counters::Counters, opcode, Count, Probe, COUNTSIZE};
+
+/// Selects the instructions a monitor probes and emits the probe code
+/// for them, to be instrumented by `instrument_sites`.
+pub trait Sites {
+    /// Returns `None` if `instr` is not probed, otherwise the number of
+    /// counters kept for it. Sites counting into counters shared by all
+    /// sites (e.g. one per opcode class) keep none of their own.
+    /// `seq_path` leads from the body of `func` down to the instruction
+    /// sequence holding `instr`, `index` is its position in there.
+    fn select(
+        &self,
+        func: &LocalFunction,
+        seq_path: &[InstrSeqId],
+        index: usize,
+        instr: &Instr,
+    ) -> Option<usize>;
+
+    /// Inserts the probe code of `instr` at position `pos` of its
+    /// instruction sequence, right before it. Its own counters start
+    /// at byte offset `offset`. The sequences nested in `instr` are
+    /// instrumented already. Returns the number of inserted instrs.
+    fn emit(
+        &self,
+        instr_builder: &mut InstrSeqBuilder,
+        pos: usize,
+        instr: &Instr,
+        offset: usize,
+        counters: &Counters,
+    ) -> walrus::Result<usize>;
+
+    /// Completes the probe recorded for a counter of `instr`, whose
+    /// `outcome` is the index of the counter among the ones of the site.
+    /// Keeps the opcode of `instr` and leaves the other fields unset
+    /// by default.
+    fn describe(
+        &self,
+        _func: &LocalFunction,
+        _seq_path: &[InstrSeqId],
+        _instr: &Instr,
+        _probe: &mut Probe,
+    ) {
+    }
+
+    /// Returns the count of a site keeping no counters of its own that
+    /// is recorded in a probe nonetheless, e.g. the counter of an
+    /// earlier site it shares. Its counters would start at byte offset
+    /// `offset`. Such sites get no probe by default, sites sharing a
+    /// count get no probe code of their own.
+    fn shared(
+        &self,
+        _func: &LocalFunction,
+        _seq_path: &[InstrSeqId],
+        _index: usize,
+        _offset: usize,
+    ) -> Option<Count> {
+        None
+    }
+}
+
+// Struct to store info on insertion locations for an instruction sequence.
+// Note that blocks can be indefinitely nested.
+#[derive(Debug)]
+struct ProbeInsertLocs {
+    id: InstrSeqId,
+
+    // (instr position, no. of counters if selected, nested ProbeInsertLocs)
+    positions: Vec<(usize, Option<usize>, Vec<ProbeInsertLocs>)>,
+}
+
+/// Adds the probes of the sites selected by `sites` to a module.
+///     1.  Counts are kept wherever the provided `Counters` put them
+///     2.  Every site gets the counters it asks for, laid out in the
+///         order of the sites in the local functions. Sites of blocks,
+///         loops and ifs come before the ones nested in them.
+///     3.  Maintain starting offset `foffset` in the memory segment for each
+///         function se we can calculate the memory offset for a
+///         site as `foffset + ioffset`.
+///     4.  Record a `Probe` for every counter so the captured memory
+///         can be decoded afterwards.
+///
+/// Returns the probes and the size (in bytes) of their counters.
+pub fn instrument_sites(
+    module: &mut Module,
+    counters: &Counters,
+    sites: &impl Sites,
+) -> walrus::Result<(Vec<Probe>, usize)> {
+    // Iterate on local functions
+    let mut curr_foffset = 0;
+    let mut probes: Vec<Probe> = Vec::new();
+    for (id, func) in module.funcs.iter_local_mut() {
+        curr_foffset +=
+            instrument_func_sites(id, func, curr_foffset, counters, sites, &mut probes)?;
+    }
+
+    Ok((probes, curr_foffset))
+}
+
+/// Adds the probes of the sites of a single local function, whose
+/// counters start at byte offset `foffset`, like `instrument_sites`.
+/// Returns the size (in bytes) of its counters.
+pub(crate) fn instrument_func_sites(
+    func_id: FunctionId,
+    func: &mut LocalFunction,
+    foffset: usize,
+    counters: &Counters,
+    sites: &impl Sites,
+    probes: &mut Vec<Probe>,
+) -> walrus::Result<usize> {
+    // Get insert locations for probe insertion
+    let entry = func.entry_block();
+    let probe_insert_locs = get_probe_insert_locs(func, entry, &[entry], sites);
+
+    // Insert probes at the locations
+    let insert_count = insert_probes(
+        func_id,
+        func,
+        &probe_insert_locs,
+        &foffset,
+        counters,
+        sites,
+        &[entry],
+        probes,
+    )?;
+
+    Ok(insert_count * COUNTSIZE)
+}
+
+fn get_probe_insert_locs(
+    func: &LocalFunction,
+    instr_seq_id: InstrSeqId,
+    seq_path: &[InstrSeqId],
+    sites: &impl Sites,
+) -> ProbeInsertLocs {
+    let mut insert_locs = ProbeInsertLocs {
+        id: instr_seq_id,
+        positions: vec![],
+    };
+
+    // Collect nested blocks alongside the path leading to them
+    let nested =
+        |seq: InstrSeqId| get_probe_insert_locs(func, seq, &[seq_path, &[seq]].concat(), sites);
+
+    for (i, (instr, _)) in func.block(instr_seq_id).iter().enumerate() {
+        let count = sites.select(func, seq_path, i, instr);
+
+        // Recurse for nested blocks
+        let blocks = match instr {
+            Instr::Block(block) => vec![nested(block.seq)],
+            Instr::Loop(block) => vec![nested(block.seq)],
+            Instr::IfElse(block) => vec![nested(block.consequent), nested(block.alternative)],
+            _ => vec![],
+        };
+
+        if count.is_some() || !blocks.is_empty() {
+            insert_locs.positions.push((i, count, blocks));
+        }
+    }
+
+    insert_locs
+}
+
+/// Insert probes at the provided insert locations
+/// Recursively does it for all nested blocks and returns
+/// total count of counters
+#[allow(clippy::too_many_arguments)]
+fn insert_probes(
+    func_id: FunctionId,
+    func: &mut LocalFunction,
+    insert_locs: &ProbeInsertLocs,
+    foffset: &usize,
+    counters: &Counters,
+    sites: &impl Sites,
+    seq_path: &[InstrSeqId],
+    probes: &mut Vec<Probe>,
+) -> walrus::Result<usize> {
+    let mut inserts_so_far: usize = 0;
+    let mut probe_count = 0;
+    for (pos_orig, count, blocks) in insert_locs.positions.iter() {
+        let ioffset = foffset + (probe_count * COUNTSIZE); // offset for storing count
+        let instr = func.block(insert_locs.id)[pos_orig + inserts_so_far]
+            .0
+            .clone();
+
+        // Record a probe for every counter of the site, or for the
+        // count it shares
+        let shared = match count {
+            Some(0) => sites.shared(func, seq_path, *pos_orig, ioffset),
+            _ => None,
+        };
+        let counts: Vec<Count> = match count {
+            Some(0) => shared.iter().cloned().collect(),
+            Some(count) => (0..*count)
+                .map(|outcome| Count::Counter(ioffset + outcome * COUNTSIZE))
+                .collect(),
+            None => vec![],
+        };
+        let opcode = opcode(&instr);
+        for (outcome, count) in counts.into_iter().enumerate() {
+            let mut probe = Probe {
+                count,
+                func: func_id,
+                seq_path: seq_path.to_vec(),
+                index: *pos_orig,
+                opcode: opcode.clone(),
+                outcome,
+                path: vec![],
+                callee: None,
+                backward: false,
+            };
+            sites.describe(func, seq_path, &instr, &mut probe);
+            probes.push(probe);
+        }
+        probe_count += count.unwrap_or(0);
+
+        // Nested sites come after the ones of the block
+        for block_insert_locs in blocks {
+            probe_count += insert_probes(
+                func_id,
+                func,
+                block_insert_locs,
+                &(foffset + (probe_count * COUNTSIZE)),
+                counters,
+                sites,
+                &[seq_path, &[block_insert_locs.id]].concat(),
+                probes,
+            )?;
+        }
+
+        // Nested positions refer to the uninstrumented blocks, so the
+        // probe code of the site (which may add to them) comes last
+        if count.is_some() && shared.is_none() {
+            let mut i = pos_orig + inserts_so_far;
+            let func_builder = func.builder_mut();
+            let mut instr_builder = func_builder.instr_seq(insert_locs.id);
+
+            // Insert probe instrs
+            i += sites.emit(&mut instr_builder, i, &instr, ioffset, counters)?;
+
+            inserts_so_far = i - pos_orig;
+        }
+    }
+
+    Ok(probe_count)
+}
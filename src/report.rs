@@ -0,0 +1,145 @@
+mod branch;
+mod callgraph;
+mod calls;
+mod heatmap;
+mod hotness;
+mod indirect;
+mod r#loop;
+mod memory;
+mod opcodes;
+mod path;
+
+use std::{collections::BTreeSet, fmt::Write};
+
+use anyhow::bail;
+use walrus::{FunctionId, Module};
+
+use crate::monitor::{
+    instrument, Builtin, Count, Format, Monitor, Options, Probe, Sampling, COUNTSIZE,
+};
+
+/// Decodes a dump of the instrument memory, captured after running
+/// a module instrumented with `monitors`, into a readable report.
+/// `module` must be the original (uninstrumented) module and
+/// `options` the ones used to instrument it so the counter layout
+/// can be recovered. The reports of several monitors follow each
+/// other under a heading and can only be written as text.
+pub fn report(
+    module: Module,
+    monitors: &[&dyn Monitor],
+    options: &Options,
+    memory: &[u8],
+) -> walrus::Result<String> {
+    if monitors.len() > 1 && !matches!(options.format, Format::Text) {
+        bail!(
+            "Reports of several monitors cannot be written as {}",
+            options.format.name()
+        );
+    }
+
+    // Instrumenting the original module again yields the exact
+    // counter layout the dump was captured with
+    let (module, probes) = instrument(module, monitors, options)?;
+
+    // Reports see the estimated counts of sampled probes
+    let scaled;
+    let memory = match options.sampling {
+        Some(sampling) => {
+            scaled = scale_counts(memory, &probes, sampling)?;
+            &scaled[..]
+        }
+        None => memory,
+    };
+
+    if let [monitor] = monitors {
+        return monitor.report(&module, &probes[0], memory, options);
+    }
+
+    let mut out = String::new();
+    for (monitor, probes) in monitors.iter().zip(&probes) {
+        writeln!(out, "== {} ==", monitor.name())?;
+        out += &monitor.report(&module, probes, memory, options)?;
+        writeln!(out)?;
+    }
+
+    Ok(out)
+}
+
+/// Writes the report of a built-in monitor.
+pub(crate) fn builtin(
+    monitor: Builtin,
+    module: &Module,
+    probes: &[Probe],
+    memory: &[u8],
+    options: &Options,
+) -> walrus::Result<String> {
+    match monitor {
+        Builtin::Hotness => hotness::report(module, probes, memory),
+        Builtin::Loop => r#loop::report(module, probes, memory),
+        Builtin::Branch => branch::report(module, probes, memory, options.predictors),
+        Builtin::Path => path::report(module, probes, memory),
+        Builtin::Calls => calls::report(module, probes, memory),
+        Builtin::Heatmap => heatmap::report(probes, memory, options),
+        Builtin::Memory => memory::report(module, probes, memory),
+        Builtin::Indirect => indirect::report(module, probes, memory),
+        Builtin::OpcodeMix => opcodes::report(probes, memory, options.format),
+        Builtin::CallGraph => callgraph::report(module, probes, memory, options.format),
+    }
+}
+
+/// Returns a copy of the dump with the counters of the probes scaled up
+/// by the sampling rate. Values are kept as they are.
+fn scale_counts(
+    memory: &[u8],
+    probes: &[Vec<Probe>],
+    sampling: Sampling,
+) -> walrus::Result<Vec<u8>> {
+    let offsets: BTreeSet<usize> = probes
+        .iter()
+        .flatten()
+        .flat_map(|probe| match &probe.count {
+            Count::Counter(offset) => vec![*offset],
+            Count::Derived(terms) => terms.iter().map(|(offset, _)| *offset).collect(),
+            Count::Value(_) => vec![],
+        })
+        .collect();
+
+    let mut scaled = memory.to_vec();
+    for offset in offsets {
+        let count = sampling.scale(read_count(memory, offset)?);
+        scaled[offset..offset + COUNTSIZE].copy_from_slice(&count.to_le_bytes());
+    }
+
+    Ok(scaled)
+}
+
+/// Reads the count stored at `offset` in the instrument memory.
+fn read_count(memory: &[u8], offset: usize) -> walrus::Result<u32> {
+    match memory.get(offset..offset + COUNTSIZE) {
+        Some(bytes) => Ok(u32::from_le_bytes(bytes.try_into().unwrap())),
+        None => bail!("Memory dump too small, no count at offset {}", offset),
+    }
+}
+
+/// Reads the count of a probe, summing up the counters it is derived
+/// from if needed. Derived counts are clamped to the range of a count.
+pub fn read_probe(memory: &[u8], probe: &Probe) -> walrus::Result<u32> {
+    match &probe.count {
+        Count::Counter(offset) | Count::Value(offset) => read_count(memory, *offset),
+        Count::Derived(terms) => {
+            let mut count: i64 = 0;
+            for (offset, factor) in terms {
+                count += read_count(memory, *offset)? as i64 * factor;
+            }
+            Ok(count.clamp(0, u32::MAX as i64) as u32)
+        }
+    }
+}
+
+/// Formats a function as `func[index] $name` for report headers.
+pub fn func_label(module: &Module, func: FunctionId) -> String {
+    match &module.funcs.get(func).name {
+        Some(name) => format!("func[{}] ${}", func.index(), name),
+        None => format!("func[{}]", func.index()),
+    }
+}
@@ -0,0 +1,139 @@
+use std::fmt::Write;
+
+use walrus::Module;
+
+use crate::monitor::Probe;
+
+use super::{func_label, read_probe};
+
+/// Predictors simulated for two-way branches: always taken, backward
+/// taken forward not taken, 1-bit and 2-bit saturating counter
+const PREDICTORS: [&str; 4] = ["always", "btfn", "1-bit", "2-bit"];
+
+/// Lists how often each path of every branch was taken, and how often
+/// predictors mispredicted the two-way ones (`if`, `br_if` and counted
+/// `select`). A branch is taken on a non-zero condition, so BTFN
+/// predicts `br_if` to a loop and `if` (a forward jump to its
+/// alternative on a zero condition) taken and all others not taken.
+/// The 1-bit and 2-bit predictors are only reported if they were
+/// simulated at runtime, i.e. with `predictors`.
+pub fn report(
+    module: &Module,
+    probes: &[Probe],
+    memory: &[u8],
+    predictors: bool,
+) -> walrus::Result<String> {
+    let mut out = String::new();
+
+    // Executions and mispredictions of all two-way branches
+    let mut executions: u64 = 0;
+    let simulated = predictors.then_some(0);
+    let mut mispredictions: [Option<u64>; 4] = [Some(0), Some(0), simulated, simulated];
+
+    for func_probes in probes.chunk_by(|a, b| a.func == b.func) {
+        // Counters of a branch are recorded next to each other
+        let branches: Vec<_> = func_probes
+            .chunk_by(|a, b| a.seq_path == b.seq_path && a.index == b.index)
+            .collect();
+
+        writeln!(
+            out,
+            "{}: {} branches",
+            func_label(module, func_probes[0].func),
+            branches.len()
+        )?;
+        writeln!(
+            out,
+            "  {:>5}  {:>5}  {:<8}  {:>6}  {:>6}  {:>6}  {:>6}  counts",
+            "depth", "index", "opcode", PREDICTORS[0], PREDICTORS[1], PREDICTORS[2], PREDICTORS[3]
+        )?;
+        for branch_probes in branches {
+            let counts = branch_probes
+                .iter()
+                .map(|probe| read_probe(memory, probe))
+                .collect::<walrus::Result<Vec<_>>>()?;
+
+            let (summary, misses) = match &counts[..] {
+                [targets @ .., default] if branch_probes[0].opcode == "br_table" => (
+                    format!("targets {:?}, default {}", targets, default),
+                    [None; 4],
+                ),
+                [taken, not_taken, predictors @ ..] => {
+                    let total = *taken as u64 + *not_taken as u64;
+                    let backward = branch_probes[0].backward || branch_probes[0].opcode == "if";
+
+                    // Mispredicted counts follow the correct ones
+                    let misses = [
+                        Some(*not_taken),
+                        Some(if backward { *not_taken } else { *taken }),
+                        predictors.get(3).copied(),
+                        predictors.get(5).copied(),
+                    ];
+                    executions += total;
+                    for (sum, miss) in mispredictions.iter_mut().zip(misses) {
+                        *sum = sum.zip(miss).map(|(sum, miss)| sum + miss as u64);
+                    }
+
+                    let summary = match total {
+                        0 => format!("taken {}, not taken {}", taken, not_taken),
+                        _ => format!(
+                            "taken {}, not taken {} ({:.1}% taken)",
+                            taken,
+                            not_taken,
+                            *taken as f64 * 100.0 / total as f64
+                        ),
+                    };
+                    (
+                        summary,
+                        misses.map(|miss| miss.map(|miss| (miss as u64, total))),
+                    )
+                }
+                _ => (format!("{:?}", counts), [None; 4]),
+            };
+
+            let rates = misses.map(rate);
+            writeln!(
+                out,
+                "  {:>5}  {:>5}  {:<8}  {:>6}  {:>6}  {:>6}  {:>6}  {}",
+                branch_probes[0].depth(),
+                branch_probes[0].index,
+                branch_probes[0].opcode,
+                rates[0],
+                rates[1],
+                rates[2],
+                rates[3],
+                summary
+            )?;
+        }
+    }
+
+    writeln!(
+        out,
+        "{} two-way branch executions, mispredicted:",
+        executions
+    )?;
+    for (name, misses) in PREDICTORS.iter().zip(mispredictions) {
+        writeln!(
+            out,
+            "  {:<6}  {}",
+            name,
+            match misses {
+                Some(misses) => format!("{} ({})", misses, rate(Some((misses, executions)))),
+                None => "not simulated".to_string(),
+            }
+        )?;
+    }
+
+    Ok(out)
+}
+
+/// Formats `misses` out of `total` as percentage, `-` if not known or
+/// never executed.
+fn rate(misses: Option<(u64, u64)>) -> String {
+    match misses {
+        Some((misses, total)) if total > 0 => {
+            format!("{:.1}%", misses as f64 * 100.0 / total as f64)
+        }
+        _ => "-".to_string(),
+    }
+}
@@ -0,0 +1,198 @@
+use std::{cmp::Reverse, collections::BTreeMap, fmt::Write};
+
+use serde_json::json;
+use walrus::{FunctionId, Module};
+
+use crate::monitor::{Format, Probe};
+
+use super::{func_label, read_probe};
+
+/// Calls along an edge of the call graph
+#[derive(Default)]
+struct Weight {
+    direct: u64,
+    indirect: u64,
+}
+
+impl Weight {
+    fn calls(&self) -> u64 {
+        self.direct + self.indirect
+    }
+}
+
+/// Writes the dynamic call graph, with every executed caller to callee
+/// edge weighted by the number of calls, as text, DOT, JSON or CSV.
+/// Indirect calls through an empty table slot or one that is not
+/// counted separately by the monitor go to an unresolved callee.
+pub fn report(
+    module: &Module,
+    probes: &[Probe],
+    memory: &[u8],
+    format: Format,
+) -> walrus::Result<String> {
+    // Sum up the calls of all sites along an edge
+    let mut edges: BTreeMap<(FunctionId, Option<FunctionId>), Weight> = BTreeMap::new();
+    for probe in probes {
+        let count = read_probe(memory, probe)? as u64;
+        if count == 0 {
+            continue;
+        }
+
+        let weight = edges.entry((probe.func, probe.callee)).or_default();
+        match &probe.opcode[..] {
+            "call" => weight.direct += count,
+            _ => weight.indirect += count,
+        }
+    }
+
+    match format {
+        Format::Text => text(module, &edges),
+        Format::Dot => dot(module, &edges),
+        Format::Json => Ok(serde_json::to_string_pretty(&graph_json(module, &edges))? + "\n"),
+        Format::Csv => csv(&edges),
+    }
+}
+
+/// Lists the edges, heaviest first.
+fn text(
+    module: &Module,
+    edges: &BTreeMap<(FunctionId, Option<FunctionId>), Weight>,
+) -> walrus::Result<String> {
+    let mut out = String::new();
+    let total: u64 = edges.values().map(|weight| weight.calls()).sum();
+    writeln!(out, "{} edges, {} calls", edges.len(), total)?;
+    writeln!(
+        out,
+        "  {:>10}  {:>10}  caller -> callee",
+        "calls", "indirect"
+    )?;
+
+    // Heaviest first, ties broken by caller and callee index
+    let mut edges: Vec<_> = edges.iter().collect();
+    edges.sort_by_key(|(_, weight)| Reverse(weight.calls()));
+    for ((caller, callee), weight) in edges {
+        writeln!(
+            out,
+            "  {:>10}  {:>10}  {} -> {}",
+            weight.calls(),
+            weight.indirect,
+            func_label(module, *caller),
+            callee_label(module, *callee)
+        )?;
+    }
+
+    Ok(out)
+}
+
+/// Writes a Graphviz digraph with a node per function and the number
+/// of calls as edge label. Edges only taken indirectly are dashed.
+fn dot(
+    module: &Module,
+    edges: &BTreeMap<(FunctionId, Option<FunctionId>), Weight>,
+) -> walrus::Result<String> {
+    let mut out = String::new();
+    writeln!(out, "digraph callgraph {{")?;
+    writeln!(out, "  node [shape=box];")?;
+
+    let mut nodes: Vec<Option<FunctionId>> = edges
+        .keys()
+        .flat_map(|(caller, callee)| [Some(*caller), *callee])
+        .collect();
+    nodes.sort();
+    nodes.dedup();
+    for node in nodes {
+        writeln!(
+            out,
+            "  {} [label=\"{}\"];",
+            node_id(node),
+            callee_label(module, node).replace('"', "\\\"")
+        )?;
+    }
+
+    for ((caller, callee), weight) in edges {
+        let calls = weight.calls();
+        let style = match weight.direct {
+            0 => ", style=dashed",
+            _ => "",
+        };
+        writeln!(
+            out,
+            "  {} -> {} [label=\"{}\", weight={}{}];",
+            node_id(Some(*caller)),
+            node_id(*callee),
+            calls,
+            calls,
+            style
+        )?;
+    }
+    writeln!(out, "}}")?;
+
+    Ok(out)
+}
+
+/// Builds a JSON document listing the functions by index and name and
+/// the edges between them. Unresolved callees are `null`.
+fn graph_json(
+    module: &Module,
+    edges: &BTreeMap<(FunctionId, Option<FunctionId>), Weight>,
+) -> serde_json::Value {
+    let mut nodes: Vec<FunctionId> = edges
+        .keys()
+        .flat_map(|(caller, callee)| [Some(*caller), *callee])
+        .flatten()
+        .collect();
+    nodes.sort();
+    nodes.dedup();
+
+    json!({
+        "nodes": nodes
+            .iter()
+            .map(|func| json!({
+                "index": func.index(),
+                "name": module.funcs.get(*func).name,
+            }))
+            .collect::<Vec<_>>(),
+        "edges": edges
+            .iter()
+            .map(|((caller, callee), weight)| json!({
+                "caller": caller.index(),
+                "callee": callee.map(|callee| callee.index()),
+                "calls": weight.calls(),
+                "indirect": weight.indirect,
+            }))
+            .collect::<Vec<_>>(),
+    })
+}
+
+/// Lists the edges by function index, leaving unresolved callees empty.
+fn csv(edges: &BTreeMap<(FunctionId, Option<FunctionId>), Weight>) -> walrus::Result<String> {
+    let mut out = String::from("caller,callee,calls,indirect\n");
+    for ((caller, callee), weight) in edges {
+        writeln!(
+            out,
+            "{},{},{},{}",
+            caller.index(),
+            callee.map_or(String::new(), |callee| callee.index().to_string()),
+            weight.calls(),
+            weight.indirect
+        )?;
+    }
+
+    Ok(out)
+}
+
+/// Formats a callee, which may be unresolved.
+fn callee_label(module: &Module, callee: Option<FunctionId>) -> String {
+    match callee {
+        Some(func) => func_label(module, func),
+        None => "unresolved".to_string(),
+    }
+}
+
+/// Graphviz node id of a function
+fn node_id(func: Option<FunctionId>) -> String {
+    match func {
+        Some(func) => format!("f{}", func.index()),
+        None => "unresolved".to_string(),
+    }
+}
@@ -0,0 +1,66 @@
+use std::fmt::Write;
+
+use walrus::Module;
+
+use crate::monitor::Probe;
+
+use super::{func_label, read_probe};
+
+/// Lists how often every local function was called, most called first,
+/// followed by the counts of the call sites of every caller if these
+/// were instrumented.
+pub fn report(module: &Module, probes: &[Probe], memory: &[u8]) -> walrus::Result<String> {
+    let mut out = String::new();
+
+    // Every function owns an entry probe followed by its call sites
+    let mut entries = probes
+        .iter()
+        .filter(|probe| probe.opcode == "entry")
+        .map(|probe| Ok((read_probe(memory, probe)?, probe)))
+        .collect::<walrus::Result<Vec<_>>>()?;
+    let total: u64 = entries.iter().map(|(count, _)| *count as u64).sum();
+
+    writeln!(out, "{} functions, {} calls", entries.len(), total)?;
+    writeln!(out, "  {:>10}  function", "calls")?;
+
+    // Most called first, ties broken by function index
+    entries.sort_by(|(a, _), (b, _)| b.cmp(a));
+    for (count, probe) in &entries {
+        writeln!(out, "  {:>10}  {}", count, func_label(module, probe.func))?;
+    }
+
+    for func_probes in probes.chunk_by(|a, b| a.func == b.func) {
+        let sites = &func_probes[1..];
+        if sites.is_empty() {
+            continue;
+        }
+
+        writeln!(
+            out,
+            "{}: {} call sites",
+            func_label(module, func_probes[0].func),
+            sites.len()
+        )?;
+        writeln!(
+            out,
+            "  {:>5}  {:>5}  {:>10}  callee",
+            "depth", "index", "calls"
+        )?;
+        for probe in sites {
+            let callee = match probe.callee {
+                Some(callee) => func_label(module, callee),
+                None => "unresolved".to_string(),
+            };
+            writeln!(
+                out,
+                "  {:>5}  {:>5}  {:>10}  {}",
+                probe.depth(),
+                probe.index,
+                read_probe(memory, probe)?,
+                callee
+            )?;
+        }
+    }
+
+    Ok(out)
+}
@@ -0,0 +1,95 @@
+use std::fmt::Write;
+
+use anyhow::bail;
+use serde_json::json;
+
+use crate::monitor::{Format, Options, Probe};
+
+use super::read_probe;
+
+/// Width of the bars of the text heatmap
+const BARWIDTH: usize = 50;
+
+/// Writes the accesses of every bucket of the main memory that was
+/// accessed, as text with a bar per bucket, CSV or JSON. Runs of
+/// untouched buckets are left out.
+pub fn report(probes: &[Probe], memory: &[u8], options: &Options) -> walrus::Result<String> {
+    let size = options.bucket_size;
+    let mut buckets = Vec::new();
+    for probe in probes {
+        let count = read_probe(memory, probe)?;
+        if count > 0 {
+            buckets.push((probe.outcome, count));
+        }
+    }
+
+    // The last probe takes accesses past the last bucket
+    let past_end = |bucket: usize| bucket + 1 == probes.len();
+    let mut out = String::new();
+    match options.format {
+        Format::Text => {
+            let total: u64 = buckets.iter().map(|(_, count)| *count as u64).sum();
+            let max = buckets.iter().map(|(_, count)| *count).max().unwrap_or(0);
+            writeln!(
+                out,
+                "{} buckets of {} bytes, {} touched, {} accesses",
+                probes.len().saturating_sub(1),
+                size,
+                buckets.len(),
+                total
+            )?;
+            writeln!(out, "  {:>23}  {:>10}", "addresses", "accesses")?;
+
+            let mut next = 0;
+            for (bucket, count) in &buckets {
+                if *bucket > next {
+                    writeln!(out, "  {:>23}", "...")?;
+                }
+                next = bucket + 1;
+
+                let range = match past_end(*bucket) {
+                    true => "out of range".to_string(),
+                    false => format!("{:#010x}..{:#010x}", bucket * size, (bucket + 1) * size),
+                };
+                let bar = (*count as u64 * BARWIDTH as u64).div_ceil(max as u64) as usize;
+                writeln!(out, "  {:>23}  {:>10}  {}", range, count, "#".repeat(bar))?;
+            }
+        }
+        Format::Csv => {
+            writeln!(out, "bucket,start,end,accesses")?;
+            for (bucket, count) in &buckets {
+                match past_end(*bucket) {
+                    true => writeln!(out, "{},,,{}", bucket, count)?,
+                    false => writeln!(
+                        out,
+                        "{},{},{},{}",
+                        bucket,
+                        bucket * size,
+                        (bucket + 1) * size,
+                        count
+                    )?,
+                }
+            }
+        }
+        Format::Json => {
+            let map = json!({
+                "bucket_size": size,
+                "buckets": buckets
+                    .iter()
+                    .map(|(bucket, count)| json!({
+                        "bucket": bucket,
+                        "start": (!past_end(*bucket)).then_some(bucket * size),
+                        "accesses": count,
+                    }))
+                    .collect::<Vec<_>>(),
+            });
+            writeln!(out, "{}", serde_json::to_string_pretty(&map)?)?;
+        }
+        Format::Dot => bail!(
+            "The heatmap report cannot be written as {}",
+            options.format.name()
+        ),
+    }
+
+    Ok(out)
+}
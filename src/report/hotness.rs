@@ -0,0 +1,53 @@
+use std::fmt::Write;
+
+use walrus::Module;
+
+use crate::monitor::Probe;
+
+use super::{func_label, read_probe};
+
+/// Number of hottest instructions listed per function
+const TOP: usize = 10;
+
+/// Lists the hottest instructions of every local function.
+pub fn report(module: &Module, probes: &[Probe], memory: &[u8]) -> walrus::Result<String> {
+    let mut out = String::new();
+    for func_probes in probes.chunk_by(|a, b| a.func == b.func) {
+        let mut counts = func_probes
+            .iter()
+            .map(|probe| Ok((read_probe(memory, probe)?, probe)))
+            .collect::<walrus::Result<Vec<_>>>()?;
+        let total: u64 = counts.iter().map(|(count, _)| *count as u64).sum();
+
+        writeln!(
+            out,
+            "{}: {} instructions, {} executed",
+            func_label(module, func_probes[0].func),
+            func_probes.len(),
+            total
+        )?;
+        if total == 0 {
+            continue;
+        }
+
+        // Hottest first, ties broken by position in the function
+        counts.sort_by(|(a, _), (b, _)| b.cmp(a));
+        writeln!(
+            out,
+            "  {:>10}  {:>5}  {:>5}  opcode",
+            "count", "depth", "index"
+        )?;
+        for (count, probe) in counts.iter().take(TOP).filter(|(count, _)| *count > 0) {
+            writeln!(
+                out,
+                "  {:>10}  {:>5}  {:>5}  {}",
+                count,
+                probe.depth(),
+                probe.index,
+                probe.opcode
+            )?;
+        }
+    }
+
+    Ok(out)
+}
@@ -0,0 +1,92 @@
+use std::{collections::BTreeSet, fmt::Write};
+
+use walrus::Module;
+
+use crate::monitor::Probe;
+
+use super::{func_label, read_probe};
+
+/// Most distinct targets a polymorphic call site may have
+const POLYMORPHIC: usize = 4;
+
+/// Lists the table slots (and the functions in them) every executed
+/// `call_indirect` site dispatched to, classifying the site by the
+/// number of distinct functions it called: monomorphic for one,
+/// polymorphic for up to `POLYMORPHIC` and megamorphic beyond. The
+/// last slot of a site, `rest`, lumps together the calls through slots
+/// that are not counted separately (past the monitor's limit or added
+/// by `table.grow`) and with out of range indices, its callee is
+/// unresolved.
+pub fn report(module: &Module, probes: &[Probe], memory: &[u8]) -> walrus::Result<String> {
+    let mut out = String::new();
+    for func_probes in probes.chunk_by(|a, b| a.func == b.func) {
+        // Every site owns a probe per table slot
+        let sites: Vec<&[Probe]> = func_probes
+            .chunk_by(|a, b| a.seq_path == b.seq_path && a.index == b.index)
+            .collect();
+        writeln!(
+            out,
+            "{}: {} indirect call sites",
+            func_label(module, func_probes[0].func),
+            sites.len()
+        )?;
+
+        for site_probes in sites {
+            let mut counts = site_probes
+                .iter()
+                .map(|probe| Ok((read_probe(memory, probe)?, probe)))
+                .collect::<walrus::Result<Vec<_>>>()?;
+            counts.retain(|(count, _)| *count > 0);
+            let total: u64 = counts.iter().map(|(count, _)| *count as u64).sum();
+
+            // Slots holding the same function count as one target
+            let targets: BTreeSet<_> = counts.iter().map(|(_, probe)| probe.callee).collect();
+            let class = match targets.len() {
+                0 => "not executed",
+                1 => "monomorphic",
+                n if n <= POLYMORPHIC => "polymorphic",
+                _ => "megamorphic",
+            };
+            writeln!(
+                out,
+                "  depth {} index {}: {} calls, {} targets, {}",
+                site_probes[0].depth(),
+                site_probes[0].index,
+                total,
+                targets.len(),
+                class
+            )?;
+            if total == 0 {
+                continue;
+            }
+
+            // Most frequent first, ties broken by slot
+            counts.sort_by(|(a, _), (b, _)| b.cmp(a));
+            writeln!(
+                out,
+                "    {:>10}  {:>7}  {:>6}  callee",
+                "calls", "share", "slot"
+            )?;
+            for (count, probe) in counts {
+                let slot = match probe.outcome {
+                    slot if slot == site_probes.len() - 1 => "rest".to_string(),
+                    slot => slot.to_string(),
+                };
+                let callee = match probe.callee {
+                    Some(callee) => func_label(module, callee),
+                    None => "unresolved".to_string(),
+                };
+                writeln!(
+                    out,
+                    "    {:>10}  {:>6.2}%  {:>6}  {}",
+                    count,
+                    count as f64 * 100.0 / total as f64,
+                    slot,
+                    callee
+                )?;
+            }
+        }
+    }
+
+    Ok(out)
+}
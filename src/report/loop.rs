@@ -0,0 +1,48 @@
+use std::fmt::Write;
+
+use walrus::Module;
+
+use crate::monitor::Probe;
+
+use super::{func_label, read_probe};
+
+/// Lists entries, iterations and the average trip count of every loop.
+pub fn report(module: &Module, probes: &[Probe], memory: &[u8]) -> walrus::Result<String> {
+    let mut out = String::new();
+    for func_probes in probes.chunk_by(|a, b| a.func == b.func) {
+        writeln!(
+            out,
+            "{}: {} loops",
+            func_label(module, func_probes[0].func),
+            func_probes.len() / 2
+        )?;
+        writeln!(
+            out,
+            "  {:>5}  {:>5}  {:>10}  {:>10}  {:>10}  {:>10}",
+            "depth", "index", "entries", "iterations", "back-edges", "avg trips"
+        )?;
+
+        // Every loop owns an entry counter followed by an iteration counter
+        for loop_probes in func_probes.chunks(2) {
+            let entries = read_probe(memory, &loop_probes[0])?;
+            let iterations = read_probe(memory, &loop_probes[1])?;
+            let avg_trips = match entries {
+                0 => "-".to_string(),
+                _ => format!("{:.2}", iterations as f64 / entries as f64),
+            };
+
+            writeln!(
+                out,
+                "  {:>5}  {:>5}  {:>10}  {:>10}  {:>10}  {:>10}",
+                loop_probes[0].depth(),
+                loop_probes[0].index,
+                entries,
+                iterations,
+                iterations.saturating_sub(entries),
+                avg_trips
+            )?;
+        }
+    }
+
+    Ok(out)
+}
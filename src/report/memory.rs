@@ -0,0 +1,82 @@
+use std::fmt::Write;
+
+use walrus::Module;
+
+use crate::monitor::Probe;
+
+use super::{func_label, read_probe};
+
+/// Lists the accesses of every executed load and store, most frequent
+/// first, with the range of addresses accessed if it was recorded.
+pub fn report(module: &Module, probes: &[Probe], memory: &[u8]) -> walrus::Result<String> {
+    let mut out = String::new();
+    for func_probes in probes.chunk_by(|a, b| a.func == b.func) {
+        // Every site owns an access counter, followed by the range
+        // counters if address ranges were recorded
+        let sites: Vec<&[Probe]> = func_probes
+            .chunk_by(|a, b| a.seq_path == b.seq_path && a.index == b.index)
+            .collect();
+        let mut counts = sites
+            .iter()
+            .map(|site_probes| Ok((read_probe(memory, &site_probes[0])?, *site_probes)))
+            .collect::<walrus::Result<Vec<_>>>()?;
+        let total: u64 = counts.iter().map(|(count, _)| *count as u64).sum();
+
+        writeln!(
+            out,
+            "{}: {} load/store sites, {} accesses",
+            func_label(module, func_probes[0].func),
+            sites.len(),
+            total
+        )?;
+        if total == 0 {
+            continue;
+        }
+
+        // Most frequent first, ties broken by position in the function
+        counts.sort_by(|(a, _), (b, _)| b.cmp(a));
+        let ranges = sites[0].len() == 3;
+        match ranges {
+            true => writeln!(
+                out,
+                "  {:>10}  {:>5}  {:>5}  {:>10}  {:>10}  {:>10}  opcode",
+                "accesses", "depth", "index", "lowest", "end", "span"
+            )?,
+            false => writeln!(
+                out,
+                "  {:>10}  {:>5}  {:>5}  opcode",
+                "accesses", "depth", "index"
+            )?,
+        }
+        for (count, site_probes) in counts.iter().filter(|(count, _)| *count > 0) {
+            let probe = &site_probes[0];
+            match ranges {
+                true => {
+                    let end = read_probe(memory, &site_probes[1])?;
+                    let lowest = !read_probe(memory, &site_probes[2])?;
+                    writeln!(
+                        out,
+                        "  {:>10}  {:>5}  {:>5}  {:>#10x}  {:>#10x}  {:>10}  {}",
+                        count,
+                        probe.depth(),
+                        probe.index,
+                        lowest,
+                        end,
+                        end.wrapping_sub(lowest),
+                        probe.opcode
+                    )?;
+                }
+                false => writeln!(
+                    out,
+                    "  {:>10}  {:>5}  {:>5}  {}",
+                    count,
+                    probe.depth(),
+                    probe.index,
+                    probe.opcode
+                )?,
+            }
+        }
+    }
+
+    Ok(out)
+}
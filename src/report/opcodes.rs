@@ -0,0 +1,69 @@
+use std::{cmp::Reverse, fmt::Write};
+
+use anyhow::bail;
+use serde_json::json;
+
+use crate::monitor::{Format, Probe};
+
+use super::read_probe;
+
+/// Writes the dynamic instruction mix: how often instructions of every
+/// opcode class were executed and their share of all executed
+/// instructions, most executed first. Classes never executed are left
+/// out. Written as text, CSV or JSON.
+pub fn report(probes: &[Probe], memory: &[u8], format: Format) -> walrus::Result<String> {
+    let mut classes = Vec::new();
+    for probe in probes {
+        let count = read_probe(memory, probe)?;
+        if count > 0 {
+            classes.push((&probe.opcode, count));
+        }
+    }
+    let total: u64 = classes.iter().map(|(_, count)| *count as u64).sum();
+
+    // Most executed first, ties broken by name
+    classes.sort_by_key(|(_, count)| Reverse(*count));
+    let share = |count: u32| count as f64 * 100.0 / total as f64;
+
+    let mut out = String::new();
+    match format {
+        Format::Text => {
+            writeln!(
+                out,
+                "{} opcode classes, {} executed, {} instructions",
+                probes.len(),
+                classes.len(),
+                total
+            )?;
+            writeln!(out, "  {:>12}  {:>6}  opcode", "executions", "share")?;
+            for (opcode, count) in &classes {
+                writeln!(out, "  {:>12}  {:>5.1}%  {}", count, share(*count), opcode)?;
+            }
+        }
+        Format::Csv => {
+            writeln!(out, "opcode,executions")?;
+            for (opcode, count) in &classes {
+                writeln!(out, "{},{}", opcode, count)?;
+            }
+        }
+        Format::Json => {
+            let map = json!({
+                "instructions": total,
+                "opcodes": classes
+                    .iter()
+                    .map(|(opcode, count)| json!({
+                        "opcode": opcode,
+                        "executions": count,
+                    }))
+                    .collect::<Vec<_>>(),
+            });
+            writeln!(out, "{}", serde_json::to_string_pretty(&map)?)?;
+        }
+        Format::Dot => bail!(
+            "The opcode mix report cannot be written as {}",
+            format.name()
+        ),
+    }
+
+    Ok(out)
+}
@@ -0,0 +1,66 @@
+use std::fmt::Write;
+
+use walrus::Module;
+
+use crate::monitor::{Probe, Span};
+
+use super::{func_label, read_probe};
+
+/// Number of hottest paths listed per function
+const TOP: usize = 10;
+
+/// Lists the hottest paths of every local function with the basic
+/// blocks along them.
+pub fn report(module: &Module, probes: &[Probe], memory: &[u8]) -> walrus::Result<String> {
+    let mut out = String::new();
+    for func_probes in probes.chunk_by(|a, b| a.func == b.func) {
+        // Functions with too many paths have a single probe
+        if func_probes[0].skipped() {
+            writeln!(
+                out,
+                "{}: {} paths, too many to profile",
+                func_label(module, func_probes[0].func),
+                func_probes[0].outcome
+            )?;
+            continue;
+        }
+
+        let mut counts = func_probes
+            .iter()
+            .map(|probe| Ok((read_probe(memory, probe)?, probe)))
+            .collect::<walrus::Result<Vec<_>>>()?;
+        let executed = counts.iter().filter(|(count, _)| *count > 0).count();
+
+        writeln!(
+            out,
+            "{}: {} paths, {} executed",
+            func_label(module, func_probes[0].func),
+            func_probes.len(),
+            executed
+        )?;
+        if executed == 0 {
+            continue;
+        }
+
+        // Hottest first, ties broken by path id
+        counts.sort_by(|(a, _), (b, _)| b.cmp(a));
+        writeln!(out, "  {:>10}  {:>5}  blocks", "count", "path")?;
+        for (count, probe) in counts.iter().take(TOP).filter(|(count, _)| *count > 0) {
+            let blocks: Vec<_> = probe.path.iter().map(span_label).collect();
+            writeln!(
+                out,
+                "  {:>10}  {:>5}  {}",
+                count,
+                probe.outcome,
+                blocks.join(" -> ")
+            )?;
+        }
+    }
+
+    Ok(out)
+}
+
+/// Formats a basic block as `seq[start..end]`.
+fn span_label(span: &Span) -> String {
+    format!("{}[{}..{}]", span.seq.index(), span.start, span.end)
+}
@@ -0,0 +1,261 @@
+use std::{
+    fmt,
+    io::{self, Write},
+};
+
+use anyhow::bail;
+use wasmtime::{Caller, Config, Engine, Extern, ExternType, FuncType, Linker, Module, Store, Val};
+
+use crate::monitor::{BASEGLOBAL, COUNTPREFIX, HOSTMODULE, MEMREGION};
+
+/// WASI modules whose imports are stubbed by the runner
+const WASI_MODULES: [&str; 2] = ["wasi_snapshot_preview1", "wasi_unstable"];
+const WASI_ERRNO_BADF: i32 = 8;
+
+/// Outcome of running an instrumented module
+pub struct Execution {
+    /// Code passed to `proc_exit`, 0 if the module returned normally
+    pub exit_code: i32,
+    /// Snapshot of the instrument memory after execution
+    pub instrument: Vec<u8>,
+    /// Accesses reported to `instr.access` by the memory monitor, as
+    /// `[id, address, size]`
+    pub trace: Vec<[u32; 3]>,
+}
+
+/// State of the host functions called by probes
+#[derive(Default)]
+struct Host {
+    /// Counts laid out like the instrument memory
+    counts: Vec<u32>,
+    /// Memory accesses in the order they happened
+    trace: Vec<[u32; 3]>,
+}
+
+/// Raised by the `proc_exit` stub to unwind out of the module.
+#[derive(Debug)]
+struct Exit(i32);
+
+impl fmt::Display for Exit {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "exited with code {}", self.0)
+    }
+}
+
+impl std::error::Error for Exit {}
+
+/// Runs the exported function `entry` of an instrumented module and
+/// snapshots the instrument memory (from the base address for the
+/// single memory backend) afterwards.
+///     1.  WASI imports are stubbed: `fd_write` writes to stdout/stderr,
+///         `proc_exit` stops execution and every other call returns 0.
+///     2.  Probe imports of the host call backend count into a buffer
+///         laid out like the instrument memory, which is returned as
+///         snapshot when the module has no instrument memory. Counts
+///         exported as globals by the globals backend are read into
+///         the same buffer, saturating at `u32::MAX`. Memory accesses
+///         reported to `instr.access` are collected in a trace.
+///     3.  Any other import is rejected as the runner cannot provide it.
+///     4.  `entry` must not take any parameters. Its results are ignored.
+pub fn run(wasm: &[u8], entry: &str) -> walrus::Result<Execution> {
+    let mut config = Config::new();
+    config.wasm_multi_memory(true);
+    let engine = Engine::new(&config)?;
+    let module = Module::new(&engine, wasm)?;
+
+    let mut linker: Linker<Host> = Linker::new(&engine);
+    define_probes(&mut linker)?;
+    for import in module.imports() {
+        if import.module() == HOSTMODULE {
+            continue;
+        }
+
+        let ty = match import.ty() {
+            ExternType::Func(ty) if WASI_MODULES.contains(&import.module()) => ty,
+            _ => bail!(
+                "Unable to provide import {}.{}",
+                import.module(),
+                import.name()
+            ),
+        };
+
+        define_wasi_stub(&mut linker, import.module(), import.name(), ty)?;
+    }
+
+    let mut store = Store::new(&engine, Host::default());
+    let instance = linker.instantiate(&mut store, &module)?;
+    let func = match instance.get_func(&mut store, entry) {
+        Some(func) => func,
+        None => bail!("Module has no exported function {}", entry),
+    };
+    if func.ty(&store).params().len() != 0 {
+        bail!("Exported function {} must not take parameters", entry);
+    }
+
+    let mut results = vec![Val::I32(0); func.ty(&store).results().len()];
+    let exit_code = match func.call(&mut store, &[], &mut results) {
+        Ok(()) => 0,
+        Err(err) => match err.downcast_ref::<Exit>() {
+            Some(Exit(code)) => *code,
+            None => return Err(err),
+        },
+    };
+
+    // Counts of the globals backend are exported as `__count_<id>`
+    let globals: Vec<_> = instance
+        .exports(&mut store)
+        .filter_map(|export| {
+            let id = export.name().strip_prefix(COUNTPREFIX)?.parse().ok()?;
+            Some((id, export.into_global()?))
+        })
+        .collect();
+    for (id, global) in globals {
+        let count = global.get(&mut store).unwrap_i64();
+        let counts = &mut store.data_mut().counts;
+        if counts.len() <= id {
+            counts.resize(id + 1, 0);
+        }
+        counts[id] = u32::try_from(count).unwrap_or(u32::MAX);
+    }
+
+    // Counts of the single memory backend start at the base address
+    let base = match instance.get_global(&mut store, BASEGLOBAL) {
+        Some(global) => global.get(&mut store).unwrap_i32() as u32 as usize,
+        None => 0,
+    };
+    let instrument = match instance.get_memory(&mut store, MEMREGION) {
+        Some(memory) => match memory.data(&store).get(base..) {
+            Some(data) => data.to_vec(),
+            None => bail!("Counts at {} lie outside of the {} memory", base, MEMREGION),
+        },
+        None => store
+            .data()
+            .counts
+            .iter()
+            .flat_map(|count| count.to_le_bytes())
+            .collect(),
+    };
+
+    Ok(Execution {
+        exit_code,
+        instrument,
+        trace: std::mem::take(&mut store.data_mut().trace),
+    })
+}
+
+/// Defines the host functions called by probes of the host call
+/// backend (and by `instrument_reset`) and by the memory monitor's
+/// trace.
+fn define_probes(linker: &mut Linker<Host>) -> walrus::Result<()> {
+    linker.func_wrap(HOSTMODULE, "probe", |caller: Caller<'_, Host>, id: i32| {
+        increment(caller, id as u32 as usize);
+    })?;
+    linker.func_wrap(
+        HOSTMODULE,
+        "branch",
+        |caller: Caller<'_, Host>, id: i32, outcome: i32| {
+            increment(caller, id as u32 as usize + outcome as u32 as usize);
+        },
+    )?;
+    linker.func_wrap(HOSTMODULE, "reset", |mut caller: Caller<'_, Host>| {
+        caller.data_mut().counts.fill(0);
+    })?;
+    linker.func_wrap(
+        HOSTMODULE,
+        "access",
+        |mut caller: Caller<'_, Host>, id: i32, address: i32, size: i32| {
+            caller
+                .data_mut()
+                .trace
+                .push([id as u32, address as u32, size as u32]);
+        },
+    )?;
+
+    Ok(())
+}
+
+/// Increments the count of probe `id`.
+fn increment(mut caller: Caller<'_, Host>, id: usize) {
+    let counts = &mut caller.data_mut().counts;
+    if counts.len() <= id {
+        counts.resize(id + 1, 0);
+    }
+    counts[id] = counts[id].wrapping_add(1);
+}
+
+/// Defines a host function for a WASI import.
+fn define_wasi_stub(
+    linker: &mut Linker<Host>,
+    module: &str,
+    name: &str,
+    ty: FuncType,
+) -> walrus::Result<()> {
+    let nparams = ty.params().len();
+    match (name, nparams) {
+        ("proc_exit", 1) => {
+            linker.func_new(module, name, ty, |_, params, _| {
+                Err(Exit(params[0].unwrap_i32()).into())
+            })?;
+        }
+        ("fd_write", 4) => {
+            linker.func_new(module, name, ty, |caller, params, results| {
+                results[0] = Val::I32(fd_write(caller, params)?);
+                Ok(())
+            })?;
+        }
+        _ => {
+            let defaults: Vec<Val> = ty
+                .results()
+                .map(|ty| Val::default_for_ty(&ty).unwrap_or(Val::I32(0)))
+                .collect();
+            linker.func_new(module, name, ty, move |_, _, results| {
+                results.clone_from_slice(&defaults);
+                Ok(())
+            })?;
+        }
+    }
+
+    Ok(())
+}
+
+/// Writes the io vectors passed to `fd_write` to stdout or stderr
+/// and returns the WASI errno.
+fn fd_write(mut caller: Caller<'_, Host>, params: &[Val]) -> walrus::Result<i32> {
+    let [fd, iovs, iovs_len, nwritten] = [0, 1, 2, 3].map(|i| params[i].unwrap_i32() as u32);
+    let memory = match caller.get_export("memory") {
+        Some(Extern::Memory(memory)) => memory,
+        _ => bail!("fd_write requires an exported memory"),
+    };
+    let data = memory.data_mut(&mut caller);
+
+    let mut out: Box<dyn Write> = match fd {
+        1 => Box::new(io::stdout()),
+        2 => Box::new(io::stderr()),
+        _ => return Ok(WASI_ERRNO_BADF),
+    };
+
+    let mut written: u32 = 0;
+    for i in 0..iovs_len {
+        let iov = wasi_slice(data, iovs + i * 8, 8)?;
+        let ptr = u32::from_le_bytes(iov[0..4].try_into().unwrap());
+        let len = u32::from_le_bytes(iov[4..8].try_into().unwrap());
+        out.write_all(wasi_slice(data, ptr, len)?)?;
+        written += len;
+    }
+    out.flush()?;
+
+    match data.get_mut(nwritten as usize..nwritten as usize + 4) {
+        Some(bytes) => bytes.copy_from_slice(&written.to_le_bytes()),
+        None => bail!("WASI call accessed memory out of bounds"),
+    }
+
+    Ok(0)
+}
+
+/// Returns `len` bytes of guest memory starting at `ptr`.
+fn wasi_slice(data: &[u8], ptr: u32, len: u32) -> walrus::Result<&[u8]> {
+    match data.get(ptr as usize..ptr as usize + len as usize) {
+        Some(bytes) => Ok(bytes),
+        None => bail!("WASI call accessed memory out of bounds"),
+    }
+}
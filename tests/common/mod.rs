@@ -0,0 +1,47 @@
+use walrus::Module;
+use wasm_bytecode_instrumenter::{
+    monitor::{instrument, Monitor, Options, Probe, Registry},
+    report::report,
+    runner::{run, Execution},
+};
+
+/// Instruments the module written in `wat` with the monitors of the
+/// comma separated `monitors` and runs its `_start` export. Returns
+/// the probes of every monitor and the execution.
+pub fn run_module(wat: &str, monitors: &str, options: &Options) -> (Vec<Vec<Probe>>, Execution) {
+    let wasm = wat::parse_str(wat).unwrap();
+    let registry = Registry::default();
+    let (mut module, probes) =
+        instrument(parse(&wasm), &lookup(&registry, monitors), options).unwrap();
+    let execution = run(&module.emit_wasm(), "_start").unwrap();
+    assert_eq!(execution.exit_code, 0);
+
+    (probes, execution)
+}
+
+/// Like `run_module`, returns the report of the counts instead.
+#[allow(dead_code)]
+pub fn run_report(wat: &str, monitors: &str, options: &Options) -> String {
+    let (_, execution) = run_module(wat, monitors, options);
+    let wasm = wat::parse_str(wat).unwrap();
+    let registry = Registry::default();
+
+    report(
+        parse(&wasm),
+        &lookup(&registry, monitors),
+        options,
+        &execution.instrument,
+    )
+    .unwrap()
+}
+
+fn lookup<'a>(registry: &'a Registry, monitors: &str) -> Vec<&'a dyn Monitor> {
+    monitors
+        .split(',')
+        .map(|name| registry.get(name).unwrap())
+        .collect()
+}
+
+fn parse(wasm: &[u8]) -> Module {
+    Module::from_buffer(wasm).unwrap()
+}
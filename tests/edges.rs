@@ -0,0 +1,74 @@
+mod common;
+
+use common::run_module;
+use wasm_bytecode_instrumenter::{
+    monitor::{Granularity, Options},
+    report::read_probe,
+};
+
+/// Nested loops dispatching through a `br_table`, with a function
+/// returning early on some of its calls
+const CONTROL_FLOW: &str = r#"
+(module
+  (func $classify (param $n i32) (result i32)
+    (if (i32.eqz (i32.rem_u (local.get $n) (i32.const 3)))
+      (then (return (i32.const 2))))
+    (block $odd
+      (block $even
+        (br_table $even $odd (i32.and (local.get $n) (i32.const 1))))
+      (return (i32.const 0)))
+    (i32.const 1))
+  (func (export "_start")
+    (local $i i32) (local $j i32) (local $sum i32)
+    (loop $outer
+      (local.set $j (i32.const 0))
+      (loop $inner
+        (local.set $sum (i32.add (local.get $sum)
+          (call $classify (i32.add (local.get $i) (local.get $j)))))
+        (br_if $inner (i32.lt_u
+          (local.tee $j (i32.add (local.get $j) (i32.const 1)))
+          (local.get $i))))
+      (br_if $outer (i32.lt_u
+        (local.tee $i (i32.add (local.get $i) (i32.const 1)))
+        (i32.const 7))))))
+"#;
+
+fn hotness(granularity: Granularity) -> Vec<(String, u32)> {
+    let options = Options {
+        granularity,
+        ..Options::default()
+    };
+    let (probes, execution) = run_module(CONTROL_FLOW, "hotness", &options);
+
+    // Probes are recorded in the same order whatever the granularity
+    probes[0]
+        .iter()
+        .map(|probe| {
+            let site = format!(
+                "func {} depth {} index {} {}",
+                probe.func.index(),
+                probe.depth(),
+                probe.index,
+                probe.opcode
+            );
+            (site, read_probe(&execution.instrument, probe).unwrap())
+        })
+        .collect()
+}
+
+#[test]
+fn edge_counts_match_instruction_counts() {
+    let counts = hotness(Granularity::Instruction);
+    assert_eq!(counts.len(), 36);
+
+    // The early returns and the br_table targets are taken
+    assert!(counts
+        .iter()
+        .any(|(site, count)| site.ends_with("return") && *count == 8));
+    assert!(counts
+        .iter()
+        .any(|(site, count)| site.ends_with("return") && *count == 6));
+
+    assert_eq!(hotness(Granularity::Edge), counts);
+    assert_eq!(hotness(Granularity::BasicBlock), counts);
+}
@@ -0,0 +1,66 @@
+mod common;
+
+use common::run_module;
+use wasm_bytecode_instrumenter::{monitor::Options, report::read_probe};
+
+/// A loop around a diamond, taking its `else` arm on even iterations
+const DIAMOND: &str = r#"
+(module
+  (func $diamond (param $n i32) (result i32)
+    (local $i i32) (local $sum i32)
+    (loop $loop
+      (if (i32.and (local.get $i) (i32.const 1))
+        (then (local.set $sum (i32.add (local.get $sum) (i32.const 1))))
+        (else (local.set $sum (i32.sub (local.get $sum) (i32.const 1)))))
+      (br_if $loop (i32.lt_u
+        (local.tee $i (i32.add (local.get $i) (i32.const 1)))
+        (local.get $n))))
+    (local.get $sum))
+  (func (export "_start")
+    (drop (call $diamond (i32.const 5)))))
+"#;
+
+// Basic blocks of `$diamond` as `(sequence id, start, end)`: the
+// sequence 0 is the body, 1 the loop and 2 and 3 the arms of the `if`
+const ENTRY: (usize, usize, usize) = (0, 0, 1);
+const HEADER: (usize, usize, usize) = (1, 0, 4);
+const THEN: (usize, usize, usize) = (2, 0, 4);
+const ELSE: (usize, usize, usize) = (3, 0, 4);
+const LATCH: (usize, usize, usize) = (1, 4, 11);
+const EXIT: (usize, usize, usize) = (0, 1, 2);
+
+#[test]
+fn diamond_in_loop_paths() {
+    let (probes, execution) = run_module(DIAMOND, "paths", &Options::default());
+    let paths: Vec<_> = probes[0]
+        .iter()
+        .filter(|probe| probe.func.index() == 0)
+        .map(|probe| {
+            let blocks: Vec<_> = probe
+                .path
+                .iter()
+                .map(|span| (span.seq.index(), span.start, span.end))
+                .collect();
+            let count = read_probe(&execution.instrument, probe).unwrap();
+            (probe.outcome, blocks, count)
+        })
+        .collect();
+
+    // Paths start at the entry or at the loop header after a back edge
+    // and end at the exit or at the back edge. The first iteration
+    // starts at the entry, the last one leaves the loop and the odd
+    // ones in between take the `then` arm.
+    assert_eq!(
+        paths,
+        vec![
+            (0, vec![ENTRY, HEADER, THEN, LATCH], 0),
+            (1, vec![ENTRY, HEADER, THEN, LATCH, EXIT], 0),
+            (2, vec![ENTRY, HEADER, ELSE, LATCH], 1),
+            (3, vec![ENTRY, HEADER, ELSE, LATCH, EXIT], 0),
+            (4, vec![HEADER, THEN, LATCH], 2),
+            (5, vec![HEADER, THEN, LATCH, EXIT], 0),
+            (6, vec![HEADER, ELSE, LATCH], 1),
+            (7, vec![HEADER, ELSE, LATCH, EXIT], 1),
+        ]
+    );
+}
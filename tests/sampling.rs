@@ -0,0 +1,42 @@
+mod common;
+
+use common::run_report;
+use wasm_bytecode_instrumenter::monitor::{Options, Sampling};
+
+/// Runs a `select` on an alternating condition 100 times and a `br_if`
+/// taken on all but 3 of 40 iterations.
+const BRANCHES: &str = r#"
+(module
+  (func (export "_start")
+    (local $i i32)
+    (loop $select
+      (drop (select (i32.const 1) (i32.const 2)
+        (i32.and (local.get $i) (i32.const 1))))
+      (br_if $select (i32.lt_u
+        (local.tee $i (i32.add (local.get $i) (i32.const 1)))
+        (i32.const 100))))
+    (local.set $i (i32.const 0))
+    (loop $branch
+      (local.set $i (i32.add (local.get $i) (i32.const 1)))
+      (br_if $branch (i32.rem_u (local.get $i) (i32.const 13)))
+      (br_if $branch (i32.lt_u (local.get $i) (i32.const 40))))))
+"#;
+
+fn sampled(period: u32, burst: u32) -> Options {
+    Options {
+        selects: true,
+        sampling: Some(Sampling::new(period, burst).unwrap()),
+        ..Options::default()
+    }
+}
+
+#[test]
+fn two_way_sites_keep_their_split() {
+    // Both outcomes of the select count down on their own, sampling
+    // every other execution of each
+    let report = run_report(BRANCHES, "branches", &sampled(2, 1));
+    assert!(report.contains("select     50.0%   50.0%       -       -  taken 50, not taken 50"));
+
+    let report = run_report(BRANCHES, "branches", &sampled(10, 1));
+    assert!(report.contains("select     50.0%   50.0%       -       -  taken 50, not taken 50"));
+}